@@ -11,26 +11,235 @@ does.
 """
 from __future__ import annotations
 
+import codecs
 import collections.abc as cabc
 import contextlib
+import copy
 import html.entities
 import io
+import itertools
+import math
 import os
 import re
+import sys
+import textwrap
+import time
+import types
 import typing as t
+import unicodedata
+import warnings
 
 import lxml.etree
 
-INDENT = b"  "
+# Look up everything the serializer uses from lxml when this module is
+# imported, so that an incompatible lxml version is reported clearly
+# instead of failing somewhere in the middle of serializing a tree.
+try:
+    for _name in (
+        "QName",
+        "_Comment",
+        "_Element",
+        "_ElementTree",
+        "_ProcessingInstruction",
+    ):
+        getattr(lxml.etree, _name)
+except AttributeError as _err:
+    raise ImportError(
+        "The XML serializer requires lxml.etree to provide QName,"
+        " _Comment, _Element, _ElementTree and _ProcessingInstruction"
+    ) from _err
+del _name
+
+INDENT_WIDTH = 2
+MAX_INDENT_WIDTH = 16
+TAB_WIDTH = 8
 LINESEP = os.linesep.encode("ascii")
 LINE_LENGTH = 80
+MAX_DEPTH = 500
+MAX_REPR_LENGTH = 60
+WRITE_CHUNK_SIZE = 1 << 16
 
 ESCAPE_CHARS = r"[\x00-\x1F\x7F{}]"
 P_ESCAPE_TEXT = re.compile(ESCAPE_CHARS.format('"&<'))
+P_ESCAPE_ELEMENT_TEXT = re.compile(ESCAPE_CHARS.format('"&<') + r"|(?<=\]\])>")
+P_ESCAPE_SINGLE_QUOTED = re.compile(ESCAPE_CHARS.format("'&<"))
+P_ESCAPE_TEXT_EMF = re.compile(r'[\x00-\x1F"&<]')
+P_ESCAPE_ELEMENT_TEXT_EMF = re.compile(r'[\x00-\x08\x0B-\x1F"&<]|(?<=\]\])>')
 P_ESCAPE_COMMENTS = re.compile(ESCAPE_CHARS.format(">"))
+P_NON_ASCII = re.compile(r"[^\x00-\x7F]")
+P_INVALID_CHARS = re.compile("[\x00-\x08\x0B\x0C\x0E-\x1F\uFFFE\uFFFF]")
+P_INVALID_CHARS_1_1 = re.compile("[\x00\uFFFE\uFFFF]")
+# NEL and LINE SEPARATOR are not restricted, but XML 1.1 parsers turn
+# them into line feeds unless they are escaped.
+P_RESTRICTED_CHARS_1_1 = re.compile("[\x80-\x9F\u2028]")
+P_NONCHARACTERS = re.compile(
+    "[\uFDD0-\uFDEF"
+    + "".join(chr(i) + chr(i + 1) for i in range(0xFFFE, 0x110000, 0x10000))
+    + "]"
+)
 P_NAME = re.compile(r"^(?:\{([^}]*)\})?(.+)$")
+P_NAMESPACE_VERSION = re.compile(r"(?:/v?\d+(?:\.\d+)*)?/*$")
+P_NCNAME = re.compile(r"[^\W\d][\w.\-\u00B7\u0300-\u036F\u203F\u2040]*")
+P_CONTROL_CHARS = re.compile(r"[\x00-\x1F\x7F-\x9F]")
+P_XMI_ID = re.compile(
+    r"_[\w-]{22}|[0-9a-f]{8}(?:-[0-9a-f]{4}){3}-[0-9a-f]{12}"
+)
+"""The shapes of IDs generated by EMF and Capella."""
 
 ALWAYS_EXPANDED_TAGS = frozenset({"bodies"})
+XML_WHITESPACE = " \t\r\n"
+LINT_MAX_ATTRIBUTE_LENGTH = 1 << 20
+CANCEL_INTERVAL = 1000
+
+PRESETS: dict[str, dict[str, t.Any]] = {
+    "capella": {"line_length": LINE_LENGTH},
+    "aird": {"line_length": None},
+    "afm": {"line_length": None},
+    "none": {},
+}
+"""Serialization options for the file types that Capella writes.
+
+They were derived from the ``.capella``, ``.aird`` and ``.afm`` files of
+the test model.
+"""
+
+NS_EXS = "urn:x-capellambse:exs"
+NS_XMI = "http://www.omg.org/XMI"
+NS_XML = "http://www.w3.org/XML/1998/namespace"
+NS_XSI = "http://www.w3.org/2001/XMLSchema-instance"
+FORMAT_MARKER = f"{{{NS_EXS}}}format"
+FRONT_ATTRIBUTES = (f"{{{NS_XMI}}}version",)
+WELL_KNOWN_PREFIXES = {NS_XMI: "xmi", NS_XSI: "xsi"}
+TYPE_ATTRIBUTES = frozenset({f"{{{NS_XSI}}}type", f"{{{NS_XMI}}}type"})
+SCHEMA_LOCATION_ATTRIBUTES = (
+    f"{{{NS_XSI}}}schemaLocation",
+    f"{{{NS_XSI}}}noNamespaceSchemaLocation",
+)
+
+
+class WriteError(OSError):
+    """Raised when the serialized XML cannot be written to a file.
+
+    The original exception is available as ``__cause__``.
+
+    Attributes
+    ----------
+    bytes_written
+        The number of bytes that were successfully written before the
+        error occurred.
+    """
+
+    def __init__(self, message: str, bytes_written: int) -> None:
+        super().__init__(message)
+        self.bytes_written = bytes_written
+
+
+class SerializationError(ValueError):
+    """Raised when a tree cannot be serialized.
+
+    Problems with the arguments passed to :func:`serialize` still raise
+    a ``TypeError`` or plain ``ValueError``.
+
+    Attributes
+    ----------
+    category
+        The kind of problem, see :attr:`Problem.category`.  Exceeding
+        the output limit uses ``"limit"``, unexpected errors inside the
+        serializer use ``"internal"`` and keep the original exception
+        as ``__cause__``, and problems that do not fall into any of
+        these categories use ``"tree"``.
+    element_path
+        The path to the node that was being serialized, or an empty
+        string if the problem is not related to a specific node.
+    attribute
+        The name of the offending attribute, or None.
+    bytes_written
+        The number of bytes that were produced before the problem was
+        found.
+    """
+
+    def __init__(
+        self,
+        message: str,
+        *,
+        category: str,
+        element_path: str = "",
+        attribute: str | None = None,
+        bytes_written: int = 0,
+    ) -> None:
+        super().__init__(message)
+        self.category = category
+        self.element_path = element_path
+        self.attribute = attribute
+        self.bytes_written = bytes_written
+
+
+class OutputLimitExceeded(SerializationError):
+    """Raised when the serialized XML exceeds ``max_output_bytes``.
+
+    Its :attr:`~SerializationError.bytes_written` are the bytes that
+    were produced before the limit would have been exceeded.
+    """
+
+    def __init__(
+        self, message: str, *, element_path: str, bytes_written: int
+    ) -> None:
+        super().__init__(
+            message,
+            category="limit",
+            element_path=element_path,
+            bytes_written=bytes_written,
+        )
+
+
+class SerializationCancelled(Exception):
+    """Raised when a serialization is cancelled or times out.
+
+    Attributes
+    ----------
+    element_path
+        The path to the element that was about to be serialized.
+    bytes_written
+        The number of bytes that were produced before the cancellation.
+    """
+
+    def __init__(
+        self, message: str, *, element_path: str, bytes_written: int
+    ) -> None:
+        super().__init__(message)
+        self.element_path = element_path
+        self.bytes_written = bytes_written
+
+
+class LintWarning(UserWarning):
+    """Content that can be serialized, but likely indicates a bug."""
+
+
+class Problem(t.NamedTuple):
+    """A problem that was found while serializing a tree."""
+
+    path: str
+    """The path to the offending node, see :func:`serialize`."""
+    category: str
+    """The kind of problem.
+
+    One of ``"node"`` for unsupported nodes, ``"type"`` for values that
+    are not strings, ``"name"`` for invalid or unresolvable names,
+    ``"character"`` for characters that cannot be written,
+    ``"comment"`` for invalid comments, or ``"fatal"`` for problems that
+    prevent serializing the tree at all.
+    """
+    message: str
+    """A description of the problem."""
+    line: int | None = None
+    """The line in the output where the problem was found.
+
+    Lines and columns are counted from 1, and columns are counted in
+    characters.  Both are None if the problem is not tied to a position
+    in the output.
+    """
+    column: int | None = None
+    """The column in the output where the problem was found."""
 
 
 @t.runtime_checkable
@@ -55,8 +264,7 @@ def to_string(tree: lxml.etree._Element, /) -> str:
     str
         The serialized XML.
     """
-    payload = serialize(tree, encoding="utf-8", errors="surrogateescape")
-    return payload.decode("utf-8", errors="surrogateescape")
+    return serialize_str(tree, errors="surrogateescape")
 
 
 def to_bytes(
@@ -88,11 +296,9 @@ def to_bytes(
     bytes
         The serialized XML, encoded using ``encoding``.
     """
-    if declare_encoding:
-        declaration = _declare(encoding)
-    else:
-        declaration = b""
-    return declaration + serialize(tree, encoding=encoding, errors=errors)
+    return serialize(
+        tree, encoding=encoding, errors=errors, declaration=declare_encoding
+    )
 
 
 def write(
@@ -102,7 +308,9 @@ def write(
     *,
     encoding: str = "utf-8",
     errors: str = "strict",
-    line_length: float | int = LINE_LENGTH,
+    newline: str | None = None,
+    preset: str = "none",
+    **kwargs: t.Any,
 ) -> None:
     """Write the XML tree to ``file``.
 
@@ -116,19 +324,69 @@ def write(
         The file encoding to use when opening a file.
     errors
         Set the encoding error handling behavior of newly opened files.
+    newline
+        The line separator to use.  See :func:`serialize`.
+    preset
+        The name of a set of options from :data:`PRESETS`, which select
+        the formatting that Capella uses for the respective file type.
+        Options passed in ``kwargs`` take precedence.
+    kwargs
+        Additional keyword arguments are passed on to :func:`serialize`.
+        Unless specified otherwise, an XML declaration is written at
+        the start of the file.
     """
+    try:
+        kwargs = {**PRESETS[preset], **kwargs}
+    except KeyError:
+        raise ValueError(f"Unknown preset: {preset!r}") from None
+
+    kwargs.setdefault("declaration", True)
+    payload = serialize(
+        tree, encoding=encoding, errors=errors, newline=newline, **kwargs
+    )
+
+    # Only open the file once the tree was serialized successfully, so
+    # that errors do not leave a truncated file behind.
     ctx: t.ContextManager[_HasWrite]
     if isinstance(file, _HasWrite):
         ctx = contextlib.nullcontext(file)
     else:
         ctx = open(file, "wb")
-
-    payload = serialize(
-        tree, encoding=encoding, errors=errors, line_length=line_length
-    )
     with ctx as f:
-        f.write(_declare(encoding))
-        f.write(payload)
+        _write_all(f, payload)
+
+
+def _write_all(file: _HasWrite, payload: bytes) -> None:
+    """Write the whole ``payload`` to ``file``, in chunks.
+
+    Short writes, like they may happen with unbuffered files, are
+    continued until everything is written.  A write that reports no
+    progress, or an impossible number of bytes, raises a
+    :class:`WriteError` instead of being retried.
+    """
+    view = memoryview(payload)
+    written = 0
+    try:
+        while written < len(view):
+            chunk = view[written : written + WRITE_CHUNK_SIZE]
+            count = file.write(chunk)
+            if count is None:
+                count = len(chunk)
+            elif not 0 < count <= len(chunk):
+                raise WriteError(
+                    f"Cannot write XML after {written} of {len(view)}"
+                    f" bytes: write() returned {count}"
+                    f" for a chunk of {len(chunk)} bytes",
+                    written,
+                )
+            written += count
+    except WriteError:
+        raise
+    except Exception as err:
+        raise WriteError(
+            f"Cannot write XML after {written} of {len(view)} bytes: {err}",
+            written,
+        ) from err
 
 
 def serialize(
@@ -137,282 +395,2822 @@ def serialize(
     *,
     encoding: str = "utf-8",
     errors: str = "strict",
-    line_length: float | int = LINE_LENGTH,
+    line_length: float | int | None = LINE_LENGTH,
+    indent_width: int = INDENT_WIDTH,
+    indent: str | None = None,
+    newline: str | None = None,
+    count_escapes: bool = True,
+    attr_per_line: bool = False,
+    attr_align: t.Literal["indent", "first"] = "indent",
+    continuation_indent: int = 2,
+    break_after_id: bool = True,
+    empty_elements: t.Literal["default", "self-close", "expand"] = "default",
+    expanded_tags: cabc.Iterable[str] | None = None,
+    trailing_newline: bool = True,
+    max_depth: int = MAX_DEPTH,
+    compact: bool = False,
+    empty_element_linebreak: bool = False,
+    wrap_attr_values: bool = False,
+    quote_char: t.Literal['"', "'"] = '"',
+    sort_attributes: bool = False,
+    attr_order: cabc.Iterable[str] = (),
+    reorder_special_attrs: bool = True,
+    xmlns_position: t.Literal[
+        "after-specials", "first", "last"
+    ] = "after-specials",
+    declaration: bool | cabc.Mapping[str, t.Any] | None = False,
+    doctype: str | None = None,
+    comments: bool = True,
+    drop_attributes: cabc.Iterable[str | lxml.etree.QName] = (),
+    omit_empty_attributes: bool = False,
+    keep_empty_attributes: cabc.Iterable[str] = (),
+    normalize_whitespace: bool = True,
+    verbatim_tags: cabc.Iterable[str] = (),
+    reindent: bool = False,
+    normalize_text_newlines: bool = False,
+    max_attrs_per_line: int | None = None,
+    dangling_bracket: bool = False,
+    space_before_self_close: bool = False,
+    keep_id_on_tag_line: bool = False,
+    banner: str | None = None,
+    base_indent: int = 0,
+    blank_line_between_root_children: bool = False,
+    minify: bool = False,
+    front_attributes: cabc.Iterable[str | lxml.etree.QName] = FRONT_ATTRIBUTES,
+    sort_children: cabc.Mapping[str, str] | None = None,
+    early_namespaces: cabc.Iterable[str] = (),
+    hoist_namespaces: bool = False,
+    prune_unused_namespaces: bool = False,
+    prefix_map: cabc.Mapping[str, str] | None = None,
+    prefix_choice: (
+        t.Literal["last-declared", "first-declared", "alphabetical"]
+        | cabc.Iterable[str]
+    ) = "last-declared",
+    xmlns_sort: t.Literal["alias", "uri"] | None = None,
+    declare_missing_namespaces: bool = False,
+    anchor_schema_location: bool = False,
+    validate_names: bool = True,
+    check_namespace_conflicts: bool | t.Literal["warn"] = False,
+    normalize_well_known_prefixes: bool = False,
+    ncr_style: t.Literal["hex", "decimal"] = "hex",
+    invalid_chars: t.Literal[
+        "error", "replace", "strip", "reference"
+    ] = "error",
+    xml_version: t.Literal["1.0", "1.1"] | None = None,
+    ascii_only: bool = False,
+    bom: bool = False,
+    fix_comments: bool = False,
+    cdata_tags: cabc.Iterable[str] = (),
+    normalize_unicode: t.Literal["NFC", "NFD", "NFKC", "NFKD"] | None = None,
+    noncharacters: t.Literal[
+        "keep", "error", "replace", "strip", "reference"
+    ] = "keep",
+    compat: t.Literal["emf"] | None = None,
+    string_errors: t.Literal["strict", "surrogateescape"] = "strict",
+    on_dropped_content: t.Literal["ignore", "warn", "error"] = "ignore",
+    problems: list[Problem] | None = None,
+    lint: bool = False,
+    lint_max_attribute_length: int = LINT_MAX_ATTRIBUTE_LENGTH,
+    lint_id_pattern: str | re.Pattern[str] = P_XMI_ID,
+    max_output_bytes: int | None = None,
+    trace: cabc.Callable[[tuple[t.Any, ...]], object] | None = None,
+    cancel: cabc.Callable[[], bool] | None = None,
+    timeout: float | None = None,
+    cancel_interval: int = CANCEL_INTERVAL,
 ) -> bytes:
     """Serialize an XML tree.
 
-    The iterator returned by this function yields the serialized XML
-    piece by piece.
+    Elements with an ``xml:space="preserve"`` attribute are serialized
+    verbatim together with their descendants, until a descendant
+    declares ``xml:space="default"``.  In such a subtree, text and
+    tails are written exactly as stored, and no line breaks or
+    indentation are inserted.  The same applies to elements whose tag is
+    listed in ``verbatim_tags``, which cannot be overridden by
+    ``xml:space``.
+
+    The layout of individual elements can be controlled by setting the
+    :data:`FORMAT_MARKER` attribute on them, which is not written to the
+    output.  A value of ``"compact"`` puts the element on a single line
+    regardless of ``line_length``, if possible, while ``"expand"`` keeps
+    it from being compacted.
 
     Parameters
     ----------
     tree
         The XML tree to serialize.
+    encoding
+        The encoding to use for the serialized output.  Characters in
+        attribute values and text that the encoding cannot represent
+        are written as character references.  Encodings that are not
+        ASCII-compatible, like UTF-16, are supported as well.
+    errors
+        How to handle errors during encoding.
+    line_length
+        The line length after which attributes are wrapped.  Pass ``0``
+        or ``None`` to never wrap attributes, which puts each opening
+        tag on a single line.
+    indent_width
+        The number of spaces to use for each level of indentation.
+    indent
+        The string to use for each level of indentation, for example
+        ``"\\t"``.  It may only consist of spaces and tabs.  If given,
+        it overrides ``indent_width``.  For the purpose of wrapping
+        attributes, tabs count as :data:`TAB_WIDTH` columns.
+    newline
+        The line separator to use, either ``"\\n"`` or ``"\\r\\n"``.
+        The default of ``None`` uses the platform's native line
+        separator, except for the trailing newline, which is always
+        ``"\\n"`` in this case.
+    count_escapes
+        Whether escaped characters count with the full length of their
+        entity or character reference (e.g. 5 columns for ``&#xA;``)
+        when deciding where to wrap attributes.  This is what Capella
+        does, and is therefore the default.  Pass ``False`` to count
+        every character of the original value as one column instead,
+        which makes wrapping independent of how much of a value needs
+        escaping.
+    attr_per_line
+        Put every attribute, including namespace declarations, on its
+        own line, regardless of ``line_length``.
+    attr_align
+        Where to place wrapped attributes.  ``"indent"`` indents them
+        by ``continuation_indent`` levels relative to the element,
+        while ``"first"`` aligns them with the first attribute in the
+        opening tag.  If the tag name alone already exceeds
+        ``line_length``, ``"first"`` falls back to ``"indent"``.
+    continuation_indent
+        The number of indentation levels by which wrapped attributes
+        are indented relative to their element.  Must be at least 1.
+    break_after_id
+        Always wrap the attributes following the root element's ``id``
+        attribute, like Capella does.  If False, only ``line_length``
+        decides where to wrap.
+    empty_elements
+        How to write elements without text or children.  With
+        ``"self-close"``, all of them are written as ``<tag/>``, and
+        with ``"expand"`` as ``<tag></tag>``.  The ``"default"`` is to
+        only expand elements listed in ``expanded_tags``.
+    expanded_tags
+        The tags of elements that are written as ``<tag></tag>`` even
+        if they are empty.  Defaults to :data:`ALWAYS_EXPANDED_TAGS`.
+        To extend the default set, pass e.g.
+        ``exs.ALWAYS_EXPANDED_TAGS | {"description"}``.  Namespaced
+        tags must be given in Clark notation, i.e. as
+        ``{uri}localname``, so that they match regardless of the
+        prefix that a particular document uses for the namespace.
+    trailing_newline
+        Whether to end the output with a line break.
+    max_depth
+        The maximum nesting depth of elements.  Deeper trees cause a
+        :class:`RecursionError`.  The default is chosen to stay clear
+        of Python's own recursion limit.
+    compact
+        Put elements with children on a single line, if the whole
+        element including all its children fits within
+        ``line_length``.  Elements containing comments or multi-line
+        text are never compacted.
+    empty_element_linebreak
+        Put the closing tag of empty elements that are written in
+        expanded form (see ``empty_elements``) on its own line, at the
+        same indentation as the opening tag.  By default, they are
+        written as ``<tag></tag>`` on a single line, which matches the
+        files saved by Capella.
+    wrap_attr_values
+        Wrap attribute values that would exceed ``line_length`` at
+        their spaces.  Because XML parsers turn line breaks in
+        attribute values into spaces, the continuation lines cannot be
+        indented without changing the value, and always start at the
+        first column.  Values without spaces are never wrapped.
+    quote_char
+        The character used to delimit attribute values, either ``"``
+        or ``'``.  Occurrences of this character in values are escaped
+        as ``&quot;`` or ``&apos;``, respectively.
+    sort_attributes
+        Sort attributes alphabetically by their qualified name, with
+        attributes without a namespace prefix coming first.  The
+        ``xmi:version`` attribute and namespace declarations are still
+        written first, unless ``reorder_special_attrs`` is False.
+    attr_order
+        Attribute names that are written before all other attributes,
+        in the given order.  Names are matched against the qualified
+        name as it appears in the output, e.g. ``name`` or ``xsi:type``.
+        Attributes that do not appear on an element are skipped.  The
+        remaining attributes follow in their usual order.
+    reorder_special_attrs
+        Apply Capella's conventions for special attributes, i.e. write
+        the ``front_attributes`` before all other attributes and always
+        wrap after the root element's ``id``.  If False, attributes are
+        written in the order in which they are stored on the element,
+        which is useful for XML files that do not originate from
+        Capella.
+    xmlns_position
+        Where to put namespace declarations among the attributes.  The
+        default ``"after-specials"`` writes them after ``xmi:version``,
+        but before all other attributes.  ``"first"`` and ``"last"``
+        write them before or after all other attributes, respectively.
+    declaration
+        Whether to start the output with an XML declaration.  Pass a
+        mapping with the keys ``version``, ``encoding`` and/or
+        ``standalone`` to control the contents of the declaration.
+        The declared encoding must match the output ``encoding``.
+    doctype
+        A complete ``<!DOCTYPE ...>`` declaration, which is written
+        verbatim after the XML declaration.  If ``tree`` is an
+        ``_ElementTree``, it defaults to the DOCTYPE from its
+        ``docinfo``, whose ``standalone`` flag is then also used for
+        the XML declaration.  Pass an empty string to omit it.
+    comments
+        Whether to include comments in the output.  If False, all
+        comments are dropped, including those before and after the
+        root element.  Text following a dropped comment is kept.
+    drop_attributes
+        Names of attributes to leave out of the output.  Namespaced
+        attributes must be given in Clark notation, for example
+        ``"{http://www.omg.org/XMI}id"``, or as QName.  The tree itself
+        is not modified.
+    omit_empty_attributes
+        Whether to leave out attributes whose value is the empty
+        string.
+    keep_empty_attributes
+        Names of attributes that are kept even if they are empty and
+        ``omit_empty_attributes`` is True.  Namespaced attributes must
+        be given in Clark notation.
+    normalize_whitespace
+        Whether to treat text and tails that consist only of whitespace
+        as absent, and indent the surrounding elements as usual.  This
+        allows re-serializing trees that were parsed without
+        ``remove_blank_text=True``.  If False, such text is written
+        verbatim.
+    verbatim_tags
+        Fully qualified tags, in Clark notation for namespaced ones, of
+        elements whose subtree is serialized verbatim.
+    reindent
+        Whether to format the tree from scratch, discarding any existing
+        indentation.  In addition to ignoring text and tails that only
+        consist of whitespace, this also collapses leading and trailing
+        whitespace around text and tails with actual content to a
+        single space, which keeps words and inline elements apart.
+        Verbatim subtrees are not affected.
+    normalize_text_newlines
+        Whether to convert carriage returns in text, tails and comments
+        to line breaks.  Both ``"\\r\\n"`` and a lone ``"\\r"`` are
+        written as a single ``newline``.  By default, carriage returns
+        are kept and escaped as ``&#xD;``, like Capella does.
+    max_attrs_per_line
+        The maximum number of attributes on each line, including the
+        line with the tag name.  Attributes are still wrapped at
+        ``line_length`` as well, whichever limit is reached first.
+        Pass ``0`` or ``None`` to not limit the number of attributes.
+    dangling_bracket
+        Whether to put the ``>`` or ``/>`` that ends an opening tag on
+        its own line, aligned with the tag, if any attributes were
+        wrapped onto a continuation line.
+    space_before_self_close
+        Whether to write a space before the ``/>`` of self-closing
+        tags, for example ``<tag name="Tag" />``.
+    keep_id_on_tag_line
+        Whether to always write the ``id`` or ``xmi:id`` attribute on
+        the same line as the tag name, even if that line then exceeds
+        ``line_length``.  Any attributes before it are kept on that line
+        as well.
+    banner
+        The text of a comment to write at the start of the document,
+        directly after the XML declaration.  It is used verbatim, so
+        it should usually start and end with a space.  Each line of a
+        multi-line banner is written on its own line.
+    base_indent
+        The indentation level of the root element, which is useful to
+        embed the output into another XML document.  All lines of the
+        tree are shifted by this many levels.  Columns are counted from
+        the start of the shifted indentation, so that ``line_length``
+        applies to the tree's own content.
+    blank_line_between_root_children
+        Whether to separate the direct children of the root element by
+        an empty line.  Comments stay attached to the element following
+        them.
+    minify
+        Whether to produce the smallest possible output, without any
+        indentation or line breaks outside of text content.  This
+        disables wrapping, the trailing newline and the default
+        ``expanded_tags``, and overrides all options that would insert
+        additional line breaks.
+    front_attributes
+        Names of the attributes to write before all others, in Clark
+        notation or as QName for namespaced ones.  They are written in
+        the given order.  Defaults to :data:`FRONT_ATTRIBUTES`, which
+        only contains ``xmi:version``.
+    sort_children
+        A mapping from tags to attribute names.  The children of
+        elements with one of the tags are written sorted by the value
+        of the respective attribute, with children that lack the
+        attribute at the end.  Comments stay in front of the child that
+        follows them.  Tags and attribute names must be given in Clark
+        notation if they are namespaced.  The tree itself is not
+        modified.
+    early_namespaces
+        Namespace URIs whose declarations are written before all other
+        ``xmlns`` attributes of an element, in the given order.  The
+        remaining declarations keep the order in which they are stored.
+    hoist_namespaces
+        Whether to declare all namespaces that are used anywhere in the
+        tree on the root element, like Capella does, instead of where
+        they are declared in the tree.  A ``ValueError`` is raised if
+        the same prefix is bound to different namespaces.
+    prune_unused_namespaces
+        Whether to leave out namespace declarations that are not used
+        by any tag or attribute within the declaring element.  A
+        namespace is also considered used if an ``xsi:type`` or
+        ``xmi:type`` value refers to its prefix.
+    prefix_map
+        A mapping from namespace URIs to the prefixes that should be
+        used for them in the output.  The ``xmlns`` declarations as well
+        as the prefixes in ``xsi:type`` and ``xmi:type`` values are
+        rewritten accordingly.  A ``ValueError`` is raised if a
+        preferred prefix is already bound to another namespace.
+    prefix_choice
+        Which prefix to use if several prefixes are bound to the same
+        namespace URI.  By default, the one that comes last in the
+        element's nsmap is used.  ``"first-declared"`` uses the one that
+        comes first instead, and ``"alphabetical"`` uses the
+        alphabetically first one.  Alternatively, an iterable of
+        prefixes can be given in the order in which they should be
+        preferred; prefixes that are not listed are used in declaration
+        order.  The declarations of all prefixes are written regardless
+        of the choice.
+    xmlns_sort
+        How to sort the ``xmlns`` declarations of an element:
+        ``"alias"`` sorts them by prefix, ``"uri"`` by namespace URI.
+        Declarations of ``early_namespaces`` are still written first.
+        By default, the declarations keep the order in which they are
+        stored.
+    declare_missing_namespaces
+        Whether to declare namespaces that an element or its attributes
+        use without them being in scope, using generated ``nsN``
+        prefixes.  By default, a ``ValueError`` naming the element and
+        the offending name is raised instead.
+    anchor_schema_location
+        Whether to write the ``xsi:schemaLocation`` and
+        ``xsi:noNamespaceSchemaLocation`` attributes right after the
+        namespace declarations, instead of in the order in which they
+        are stored.
+    validate_names
+        Whether to check that all namespace prefixes are valid NCNames,
+        raising a ``ValueError`` for the first invalid one.  Trusted
+        callers can turn this off to save some time.
+    check_namespace_conflicts
+        Whether to check for namespace URIs that only differ in a
+        trailing version segment or slash, which usually means that
+        different versions of the same metamodel are mixed.  If True,
+        a ``ValueError`` is raised for such conflicts; if ``"warn"``, a
+        warning is emitted instead.
+    normalize_well_known_prefixes
+        Whether to always use the canonical prefixes from
+        :data:`WELL_KNOWN_PREFIXES`, i.e. ``xmi`` and ``xsi``, for their
+        namespaces.  This takes precedence over the ``prefix_map``.  A
+        ``ValueError`` is raised if one of these prefixes is already
+        bound to another namespace.
+    ncr_style
+        Whether to write numeric character references in hexadecimal,
+        like ``&#xA;``, as Capella does, or in decimal, like ``&#10;``.
+    invalid_chars
+        How to handle characters that cannot be represented in the
+        ``xml_version``, not even as character references, like
+        ``U+0000``.
+        ``"error"`` raises a ``ValueError`` naming the element and
+        attribute, ``"replace"`` substitutes ``U+FFFD``, ``"strip"``
+        leaves them out, and ``"reference"`` writes them as character
+        references anyway, which produces a document that XML parsers
+        reject.
+    xml_version
+        The XML version to write, either ``"1.0"`` or ``"1.1"``.  It is
+        used for the XML declaration, and XML 1.1 allows writing most
+        control characters as character references, except for
+        ``U+0000``.  Defaults to the ``version`` of the ``declaration``,
+        or ``"1.0"``.
+    ascii_only
+        Whether to write all non-ASCII characters in attribute values
+        and text as character references.  Line lengths are then always
+        measured after escaping.  As comments cannot contain character
+        references, a ``ValueError`` is raised for non-ASCII comments.
+    bom
+        Whether to start the output with a UTF-8 byte order mark, which
+        some Windows tools need to detect the encoding.  This is only
+        supported with the UTF-8 ``encoding``.
+    fix_comments
+        Whether to fix comments that contain ``--`` or end with ``-``,
+        which XML does not allow, by inserting spaces.  By default, a
+        ``ValueError`` is raised for them.
+    cdata_tags
+        Fully qualified tags of elements whose text is written as a
+        CDATA section instead of being escaped.  Text that contains
+        ``]]>`` is split into several adjacent sections.
+    normalize_unicode
+        A Unicode normalization form, which is applied to text, tails,
+        comments and attribute values before writing them.  Use
+        ``"NFC"`` to avoid spurious differences between composed and
+        decomposed spellings of the same characters.  By default, all
+        strings are written as they are.
+    noncharacters
+        How to handle Unicode noncharacters, like ``U+FDD0`` or
+        ``U+1FFFE``, which XML discourages and some strict parsers
+        reject.  ``"keep"`` writes them as they are, the other values
+        work like for ``invalid_chars``.  ``U+FFFE`` and ``U+FFFF`` are
+        never allowed in XML and follow ``invalid_chars`` instead.
+    compat
+        Pass ``"emf"`` to escape attribute values, text and tails
+        exactly like the EMF serializer used by Capella.  Unlike the
+        default, it writes ``U+007F`` as it is, and does not escape
+        tabs in text.  It cannot be combined with single quotes.
+    string_errors
+        How to handle lone surrogates in the strings of the tree.  By
+        default, they are handled according to ``errors``.  Pass
+        ``"surrogateescape"`` for strings that were decoded with the
+        error handler of the same name, to write them back as the
+        original bytes.  This requires a UTF-8 ``encoding``.
+    on_dropped_content
+        What to do if ``tree`` is an element with comments or
+        processing instructions next to it, which are only serialized
+        when passing the ``_ElementTree``.  ``"ignore"`` silently
+        leaves them out, ``"warn"`` emits a warning listing them, and
+        ``"error"`` raises a ``ValueError``.
+    problems
+        A list to collect problems in, instead of raising an exception
+        for the first one.  Each problem is recorded as a
+        :class:`Problem`, and the offending node or value is skipped or
+        replaced, so that the output is still well-formed.  Text with
+        an invalid character is left out entirely, and an attribute
+        value with one is written as an empty string.
+        Errors that prevent serializing the tree at all, like a root
+        that is not an element, are still raised.
+    lint
+        Emit a :class:`LintWarning` for content that can be serialized,
+        but most likely indicates a bug in the code that built the
+        tree: control characters in names, overly long attribute
+        values, and ``xmi:id`` values that are not shaped like a UUID.
+    lint_max_attribute_length
+        The number of characters above which an attribute value is
+        considered overly long in ``lint`` mode.
+    lint_id_pattern
+        The pattern that ``xmi:id`` values must fully match in ``lint``
+        mode.  The default accepts the IDs generated by EMF and the
+        UUIDs generated by Capella.
+    max_output_bytes
+        The maximum size of the serialized XML in bytes.  If the output
+        would grow beyond this size, serialization stops and an
+        :class:`OutputLimitExceeded` is raised.  For encodings that are
+        not ASCII compatible, like UTF-16, the limit applies to the
+        UTF-8 encoded intermediate output.
+    trace
+        A callable that receives events as the serialization proceeds,
+        to find out why the output is formatted the way it is.  Each
+        event is a tuple of its name and some details:
+
+        - ``("start_element", tag, indent)`` when starting an element
+        - ``("compact", tag, fits)`` after trying to write an element
+          with its children on a single line
+        - ``("wrap", attribute, column)`` when breaking the line before
+          an attribute
+        - ``("flush", nbytes)`` when returning the serialized XML
+    cancel
+        A callable that is polled periodically during serialization.
+        If it returns True, serialization stops and a
+        :class:`SerializationCancelled` is raised.
+    timeout
+        The maximum time in seconds that the serialization may take,
+        after which it is cancelled like with ``cancel``.
+    cancel_interval
+        The number of elements, attributes and lines of text to
+        serialize between two checks of ``cancel`` and ``timeout``.
+        Lower values cancel more promptly, but add more overhead.
 
     Returns
     -------
-    Iterator[str]
-        An iterator that yields the serialized XML piece by piece.
+    bytes
+        The serialized XML.
     """
-    buffer = io.BytesIO()
-    root: lxml.etree._Element
-    preceding_siblings: cabc.Iterable[lxml.etree._Comment]
-    following_siblings: cabc.Iterable[lxml.etree._Comment]
-    if isinstance(tree, lxml.etree._ElementTree):
-        root = tree.getroot()
-        preceding_siblings = reversed(list(root.itersiblings(preceding=True)))
-        following_siblings = root.itersiblings()
+    if not isinstance(tree, (lxml.etree._Element, lxml.etree._ElementTree)):
+        raise TypeError(
+            "Expected an lxml element or element tree,"
+            f" not {type(tree).__name__}{_suggest_input(tree)}"
+        )
+    if attr_align not in ("indent", "first"):
+        raise ValueError(f"Unsupported attr_align: {attr_align!r}")
+    if empty_elements not in ("default", "self-close", "expand"):
+        raise ValueError(f"Unsupported empty_elements: {empty_elements!r}")
+    if xmlns_position not in ("after-specials", "first", "last"):
+        raise ValueError(f"Unsupported xmlns_position: {xmlns_position!r}")
+    if check_namespace_conflicts not in (False, True, "warn"):
+        raise ValueError(
+            "Unsupported check_namespace_conflicts:"
+            f" {check_namespace_conflicts!r}"
+        )
+    if ncr_style not in ("hex", "decimal"):
+        raise ValueError(f"Unsupported ncr_style: {ncr_style!r}")
+    if invalid_chars not in ("error", "replace", "strip", "reference"):
+        raise ValueError(f"Unsupported invalid_chars: {invalid_chars!r}")
+    if noncharacters not in ("keep", "error", "replace", "strip", "reference"):
+        raise ValueError(f"Unsupported noncharacters: {noncharacters!r}")
+    if normalize_unicode not in (None, "NFC", "NFD", "NFKC", "NFKD"):
+        raise ValueError(
+            f"Unsupported normalize_unicode: {normalize_unicode!r}"
+        )
+    if xmlns_sort not in (None, "alias", "uri"):
+        raise ValueError(f"Unsupported xmlns_sort: {xmlns_sort!r}")
+    if quote_char not in ('"', "'"):
+        raise ValueError(f"Unsupported quote_char: {quote_char!r}")
+    if compat not in (None, "emf"):
+        raise ValueError(f"Unsupported compat: {compat!r}")
+    if on_dropped_content not in ("ignore", "warn", "error"):
+        raise ValueError(
+            f"Unsupported on_dropped_content: {on_dropped_content!r}"
+        )
+    if compat == "emf" and quote_char != '"':
+        raise ValueError("EMF compatibility requires double quotes")
+    if max_attrs_per_line is not None and max_attrs_per_line < 0:
+        raise ValueError(
+            "max_attrs_per_line must not be negative,"
+            f" not {max_attrs_per_line}"
+        )
+    if max_output_bytes is not None and max_output_bytes < 0:
+        raise ValueError(
+            f"max_output_bytes must not be negative, not {max_output_bytes}"
+        )
+    if timeout is not None and timeout < 0:
+        raise ValueError(f"timeout must not be negative, not {timeout}")
+    if cancel_interval < 1:
+        raise ValueError(
+            f"cancel_interval must be at least 1, not {cancel_interval}"
+        )
+    if timeout is not None:
+        cancel = _with_deadline(cancel, time.monotonic() + timeout)
+    if lint_max_attribute_length < 0:
+        raise ValueError(
+            "lint_max_attribute_length must not be negative,"
+            f" not {lint_max_attribute_length}"
+        )
+    if base_indent < 0:
+        raise ValueError(
+            f"base_indent must not be negative, not {base_indent}"
+        )
+    if continuation_indent < 1:
+        raise ValueError(
+            "continuation_indent must be at least 1,"
+            f" not {continuation_indent}"
+        )
+    prefix_map = dict(prefix_map or {})
+    if normalize_well_known_prefixes:
+        prefix_map.update(WELL_KNOWN_PREFIXES)
+    if invalid := [
+        i for i in prefix_map.values() if not i or ":" in i or i == "xml"
+    ]:
+        raise ValueError(f"Invalid prefixes in prefix_map: {invalid!r}")
+    if isinstance(prefix_choice, str):
+        if prefix_choice not in (
+            "last-declared",
+            "first-declared",
+            "alphabetical",
+        ):
+            raise ValueError(f"Unsupported prefix_choice: {prefix_choice!r}")
     else:
-        root = tree
-        preceding_siblings = ()
-        following_siblings = ()
+        prefix_choice = {prefix: i for i, prefix in enumerate(prefix_choice)}
+    if minify:
+        line_length = indent = None
+        indent_width = 0
+        attr_per_line = blank_line_between_root_children = False
+        empty_element_linebreak = trailing_newline = False
+        max_attrs_per_line = None
+    if expanded_tags is None:
+        expanded = frozenset() if minify else ALWAYS_EXPANDED_TAGS
+    else:
+        expanded = frozenset(expanded_tags)
+        if invalid := [i for i in expanded if not isinstance(i, str)]:
+            raise TypeError(
+                f"expanded_tags must only contain strings, not {invalid!r}"
+            )
+    if indent is None:
+        if not 0 <= indent_width <= MAX_INDENT_WIDTH:
+            raise ValueError(
+                f"indent_width must be between 0 and {MAX_INDENT_WIDTH},"
+                f" not {indent_width}"
+            )
+        indent = " " * indent_width
+    elif len(indent) > MAX_INDENT_WIDTH:
+        raise ValueError(
+            f"indent must be at most {MAX_INDENT_WIDTH} characters long,"
+            f" not {len(indent)}"
+        )
+    elif indent.strip(" \t"):
+        raise ValueError(
+            f"indent must only contain spaces and tabs, not {indent!r}"
+        )
 
-    pos = 0
-    for i in preceding_siblings:
-        pos = _serialize_comment(
-            buffer, i, encoding=encoding, errors=errors, pos=pos, indent=0
+    standalone = None
+    if isinstance(tree, lxml.etree._ElementTree):
+        if doctype is None:
+            doctype = tree.docinfo.doctype
+        standalone = tree.docinfo.standalone
+    if banner is not None and ("--" in banner or banner.endswith("-")):
+        raise ValueError(f"Banner is not a valid comment: {banner!r}")
+    if doctype and not (
+        doctype.startswith("<!DOCTYPE") and doctype.endswith(">")
+    ):
+        raise ValueError(f"Invalid DOCTYPE declaration: {doctype!r}")
+
+    if not isinstance(declaration, cabc.Mapping):
+        declaration = {} if declaration else None
+    declared_version = (declaration or {}).get("version", xml_version)
+    if xml_version is None:
+        xml_version = declared_version or "1.0"
+    if xml_version not in ("1.0", "1.1"):
+        raise ValueError(f"Unsupported xml_version: {xml_version!r}")
+    if declared_version not in (None, xml_version):
+        raise ValueError(
+            f"Cannot declare version {declared_version!r}"
+            f" when writing XML {xml_version}"
         )
+    if declaration is None:
+        xml_declaration = b""
+    else:
+        if unknown := set(declaration) - {"version", "encoding", "standalone"}:
+            raise ValueError(
+                f"Unknown keys in declaration: {', '.join(sorted(unknown))}"
+            )
+        declared_encoding = declaration.get("encoding", encoding)
+        if codecs.lookup(declared_encoding) != codecs.lookup(encoding):
+            raise ValueError(
+                f"Cannot declare encoding {declared_encoding!r}"
+                f" when serializing as {encoding!r}"
+            )
+        xml_declaration = _declare(
+            declared_encoding,
+            newline=newline,
+            version=xml_version,
+            standalone=declaration.get("standalone", standalone),
+        )
+        if minify:
+            xml_declaration = xml_declaration.rstrip(b"\r\n")
+
+    if bom and codecs.lookup(encoding).name != "utf-8":
+        raise ValueError(f"Cannot write a UTF-8 BOM as {encoding!r}")
+    if string_errors == "surrogateescape":
+        if codecs.lookup(encoding).name != "utf-8":
+            raise ValueError(
+                f"Cannot restore surrogate escapes when writing {encoding!r}"
+            )
+        if ascii_only:
+            raise ValueError(
+                "Cannot restore surrogate escapes in ASCII-only mode"
+            )
+        if errors not in ("strict", "surrogateescape"):
+            raise ValueError(
+                f"Cannot combine string_errors={string_errors!r}"
+                f" with errors={errors!r}"
+            )
+        errors = "surrogateescape"
+    elif string_errors != "strict":
+        raise ValueError(f"Unsupported string_errors: {string_errors!r}")
+    if codecs.lookup(encoding).name.startswith("utf"):
+        charset = None
+    else:
+        charset = encoding
+    transcode = "<a>".encode(encoding) != b"<a>"
 
-    _serialize_element(
+    buffer: io.BytesIO
+    if max_output_bytes is None:
+        buffer = io.BytesIO()
+    else:
+        buffer = _LimitedBuffer(max_output_bytes)
+    serializer = _Serializer(
         buffer,
-        root,
-        0,
-        encoding=encoding,
+        encoding="utf-8" if transcode else encoding,
+        charset=charset,
         errors=errors,
         line_length=line_length,
-        pos=pos,
+        indent=indent,
+        linesep=_linesep(newline),
+        count_escapes=count_escapes,
+        attr_per_line=attr_per_line,
+        attr_align=attr_align,
+        continuation_indent=continuation_indent,
+        break_after_id=break_after_id,
+        empty_elements=empty_elements,
+        expanded_tags=expanded,
+        trailing_newline=trailing_newline,
+        trailing_linesep=b"\n" if newline is None else _linesep(newline),
+        max_depth=max_depth,
+        compact=compact,
+        empty_element_linebreak=empty_element_linebreak,
+        wrap_attr_values=wrap_attr_values,
+        quote_char=quote_char,
+        sort_attributes=sort_attributes,
+        attr_order=attr_order,
+        reorder_special_attrs=reorder_special_attrs,
+        xmlns_position=xmlns_position,
+        declaration=xml_declaration,
+        doctype=doctype or "",
+        comments=comments,
+        drop_attributes=frozenset(map(str, drop_attributes)),
+        omit_empty_attributes=omit_empty_attributes,
+        keep_empty_attributes=frozenset(keep_empty_attributes),
+        normalize_whitespace=normalize_whitespace,
+        verbatim_tags=frozenset(verbatim_tags),
+        reindent=reindent,
+        normalize_text_newlines=normalize_text_newlines,
+        max_attrs_per_line=max_attrs_per_line or math.inf,
+        dangling_bracket=dangling_bracket,
+        space_before_self_close=space_before_self_close,
+        keep_id_on_tag_line=keep_id_on_tag_line,
+        banner=banner,
+        base_indent=base_indent,
+        blank_line_between_root_children=blank_line_between_root_children,
+        minify=minify,
+        front_attributes=tuple(map(str, front_attributes)),
+        sort_children=dict(sort_children or {}),
+        early_namespaces=early_namespaces,
+        hoist_namespaces=hoist_namespaces,
+        prune_unused_namespaces=prune_unused_namespaces,
+        prefix_map=prefix_map,
+        prefix_choice=prefix_choice,
+        xmlns_sort=xmlns_sort,
+        declare_missing_namespaces=declare_missing_namespaces,
+        anchor_schema_location=anchor_schema_location,
+        validate_names=validate_names,
+        check_namespace_conflicts=check_namespace_conflicts,
+        ncr_style=ncr_style,
+        invalid_chars=invalid_chars,
+        xml_version=xml_version,
+        ascii_only=ascii_only,
+        fix_comments=fix_comments,
+        cdata_tags=frozenset(map(str, cdata_tags)),
+        normalize_unicode=normalize_unicode,
+        noncharacters=noncharacters,
+        compat=compat,
+        on_dropped_content=on_dropped_content,
+        problems=problems,
+        lint=lint,
+        lint_max_attribute_length=lint_max_attribute_length,
+        lint_id_pattern=lint_id_pattern,
+        trace=trace,
+        cancel=cancel,
+        cancel_interval=cancel_interval,
     )
-    if (root.tail or "").strip():
-        pos = _serialize_text(
-            buffer,
-            root.tail,
-            encoding=encoding,
-            errors=errors,
-            pos=pos,
-            multiline=True,
+    try:
+        if bom:
+            buffer.write(codecs.BOM_UTF8)
+        serializer.serialize_tree(tree)
+    except _CallbackError as err:
+        raise err.error from err.error.__cause__
+    except Warning:
+        # Warnings that are turned into errors by a warnings filter
+        raise
+    except _Cancelled:
+        message = f"Serialization was cancelled after {buffer.tell()} bytes"
+        if (node := serializer.breadcrumb[0]) is None:
+            path = ""
+        else:
+            path = _describe_path(node)
+            message += f"\n  at {path}"
+        raise SerializationCancelled(
+            message, element_path=path, bytes_written=buffer.tell()
+        ) from None
+    except _OutputLimitReached:
+        message = (
+            f"The output exceeds the limit of {max_output_bytes} bytes"
+            f" after {buffer.tell()} bytes"
+        )
+        if (node := serializer.breadcrumb[0]) is None:
+            path = ""
+        else:
+            path = _describe_path(node)
+            message += f"\n  at {path}"
+        raise OutputLimitExceeded(
+            message, element_path=path, bytes_written=buffer.tell()
+        ) from None
+    except (TypeError, ValueError, RecursionError) as err:
+        if (node := serializer.breadcrumb[0]) is None:
+            raise
+        position = _describe_position(
+            buffer.getvalue(), serializer.linesep, serializer.encoding
+        )
+        raise _locate(err, node, buffer.tell(), position) from None
+    except MemoryError:
+        raise
+    except Exception as err:
+        message = f"Internal error: {type(err).__name__}: {err}"
+        if (node := serializer.breadcrumb[0]) is None:
+            path = ""
+        else:
+            path = _describe_path(node)
+            position = _describe_position(
+                buffer.getvalue(), serializer.linesep, serializer.encoding
+            )
+            message += f"\n  at {position}\n  at {path}"
+        raise SerializationError(
+            message,
+            category="internal",
+            element_path=path,
+            bytes_written=buffer.tell(),
+        ) from err
+    finally:
+        serializer.report_problems()
+    if transcode:
+        payload = buffer.getvalue().decode("utf-8", "surrogateescape")
+        result = payload.encode(encoding, errors)
+    else:
+        result = buffer.getvalue()
+    if trace is not None:
+        trace(("flush", len(result)))
+    return result
+
+
+def serialize_str(
+    tree: lxml.etree._Element | lxml.etree._ElementTree,
+    /,
+    **kwargs: t.Any,
+) -> str:
+    """Serialize an XML tree into a ``str``.
+
+    This is equivalent to ``serialize(tree, **kwargs).decode()``.  The
+    output is still serialized into ``bytes`` first, so while the
+    ``str`` is built, both copies are held in memory.
+
+    Parameters
+    ----------
+    tree
+        The XML tree to serialize.
+    **kwargs
+        Options for the serializer, see :func:`serialize`.  The
+        ``encoding`` and ``bom`` options are not supported, as they
+        only apply to bytes.
+
+    Returns
+    -------
+    str
+        The serialized XML.
+    """
+    if unsupported := {"encoding", "bom"} & kwargs.keys():
+        raise TypeError(
+            "serialize_str() got unsupported keyword arguments:"
+            f" {', '.join(sorted(unsupported))}"
         )
+    if kwargs.get("string_errors") == "surrogateescape":
+        errors = "surrogateescape"
+    else:
+        errors = kwargs.get("errors", "strict")
+    payload = serialize(tree, encoding="utf-8", **kwargs)
+    return payload.decode("utf-8", errors)
+
+
+def validate(
+    tree: lxml.etree._Element | lxml.etree._ElementTree,
+    /,
+    **kwargs: t.Any,
+) -> list[Problem]:
+    """Find all problems that prevent serializing a tree.
+
+    This performs a full serialization and discards the output.  An
+    empty list therefore means that :func:`serialize` with the same
+    options does not fail because of the tree.  It can still be
+    cancelled, and :func:`write` can still fail to write the output.
+
+    Parameters
+    ----------
+    tree
+        The XML tree to check.
+    **kwargs
+        Options for the serializer, see :func:`serialize`.  The
+        ``problems`` option is not supported, as the problems are
+        returned instead.  Options that only cause side effects are
+        ignored: ``trace``, ``cancel``, ``timeout`` and ``lint`` have
+        no effect, and no warnings are emitted for
+        ``on_dropped_content`` or ``check_namespace_conflicts``.
 
-    for i in following_siblings:
-        pos = _serialize_comment(
-            buffer, i, encoding=encoding, errors=errors, pos=pos, indent=0
+    Returns
+    -------
+    list[Problem]
+        The problems that were found, in document order.  If the tree
+        cannot be serialized at all, the last problem is of category
+        ``"fatal"``.
+    """
+    if "problems" in kwargs:
+        raise TypeError(
+            "validate() got an unsupported keyword argument: problems"
         )
+    kwargs.update(trace=None, cancel=None, timeout=None, lint=False)
+    if kwargs.get("on_dropped_content") == "warn":
+        kwargs["on_dropped_content"] = "ignore"
+    if kwargs.get("check_namespace_conflicts") == "warn":
+        kwargs["check_namespace_conflicts"] = False
+    problems: list[Problem] = []
+    try:
+        serialize(tree, problems=problems, **kwargs)
+    except (TypeError, ValueError, RecursionError) as err:
+        problems.append(Problem("", "fatal", str(err)))
+    return problems
 
-    buffer.write(b"\n")
-    return buffer.getvalue()
 
+def collect_namespaces(
+    tree: lxml.etree._Element | lxml.etree._ElementTree, /
+) -> dict[str, tuple[set[str | None], int]]:
+    """Find out which namespaces are referenced in an XML tree.
 
-def _declare(encoding: str) -> bytes:
+    Parameters
+    ----------
+    tree
+        The XML tree to inspect.
+
+    Returns
+    -------
+    dict[str, tuple[set[str | None], int]]
+        A mapping from namespace URIs to the prefixes that are used for
+        them and the number of tags and attributes in that namespace.
+        Tags in a default namespace are counted with the prefix None.
+        If several prefixes are bound to a namespace, the one that
+        :func:`serialize` writes by default is counted.
+    """
+    if isinstance(tree, lxml.etree._ElementTree):
+        tree = tree.getroot()
+
+    usage: dict[str, tuple[set[str | None], int]] = {}
+    for element in tree.iter():
+        if not isinstance(element.tag, str):
+            continue
+        nsmap = element.nsmap
+        chosen = _choose_prefixes(nsmap, "last-declared")
+        names = itertools.chain((element.tag,), element.keys())
+        for i, name in enumerate(names):
+            if not name.startswith("{"):
+                continue
+            uri = name[1 : name.index("}")]
+            prefix: str | None
+            if uri == NS_XML:
+                prefix = "xml"
+            elif i == 0 and nsmap.get(None) == uri:
+                prefix = None
+            else:
+                prefix = chosen.get(uri)
+            prefixes, count = usage.get(uri, (set(), 0))
+            prefixes.add(prefix)
+            usage[uri] = (prefixes, count + 1)
+    return usage
+
+
+def escape(
+    text: str,
+    /,
+    *,
+    context: t.Literal["attribute", "text", "comment"] = "attribute",
+    ncr_style: t.Literal["hex", "decimal"] = "hex",
+    invalid_chars: t.Literal[
+        "error", "replace", "strip", "reference"
+    ] = "error",
+    xml_version: t.Literal["1.0", "1.1"] = "1.0",
+    ascii_only: bool = False,
+    noncharacters: t.Literal[
+        "keep", "error", "replace", "strip", "reference"
+    ] = "keep",
+    compat: t.Literal["emf"] | None = None,
+) -> str:
+    """Escape a string the same way the serializer does.
+
+    Parameters
+    ----------
+    text
+        The string to escape.
+    context
+        Where the string will be written.  ``"attribute"`` escapes it
+        as a double-quoted attribute value, including line breaks.
+        ``"text"`` escapes it as element text, where line breaks are
+        written as they are and the ``>`` of a ``]]>`` is escaped.
+        ``"comment"`` prepares it as the body of a comment, where
+        nothing is escaped because character references are not
+        recognized there.
+    ncr_style
+        Whether to write numeric character references in hexadecimal,
+        like ``&#xA;``, or in decimal, like ``&#10;``.
+    invalid_chars
+        How to handle characters that cannot be represented in XML,
+        not even as character references.  See :func:`serialize`.
+    xml_version
+        The XML version to escape for.  XML 1.1 allows most control
+        characters as character references, and requires them for the
+        C1 control characters.  ``U+2028`` is escaped as well, because
+        XML 1.1 parsers turn it into a line feed otherwise.
+    ascii_only
+        Whether to escape all non-ASCII characters as character
+        references.  Comments cannot contain references, so a
+        ``ValueError`` is raised for non-ASCII comments instead.
+    noncharacters
+        How to handle Unicode noncharacters.  See :func:`serialize`.
+    compat
+        Pass ``"emf"`` to escape like EMF does.  See :func:`serialize`.
+
+    Returns
+    -------
+    str
+        The escaped string.
+    """
+    if ncr_style not in ("hex", "decimal"):
+        raise ValueError(f"Unsupported ncr_style: {ncr_style!r}")
+    if invalid_chars not in ("error", "replace", "strip", "reference"):
+        raise ValueError(f"Unsupported invalid_chars: {invalid_chars!r}")
+    if xml_version not in ("1.0", "1.1"):
+        raise ValueError(f"Unsupported xml_version: {xml_version!r}")
+    if noncharacters not in ("keep", "error", "replace", "strip", "reference"):
+        raise ValueError(f"Unsupported noncharacters: {noncharacters!r}")
+    if compat not in (None, "emf"):
+        raise ValueError(f"Unsupported compat: {compat!r}")
+    if context == "attribute":
+        pattern = P_ESCAPE_TEXT_EMF if compat else P_ESCAPE_TEXT
+    elif context == "text" and compat:
+        pattern = P_ESCAPE_ELEMENT_TEXT_EMF
+    elif context == "text":
+        pattern = P_ESCAPE_ELEMENT_TEXT
+    elif context == "comment":
+        if ascii_only and not text.isascii():
+            raise ValueError(
+                f"Cannot escape non-ASCII comment in ASCII-only mode: {text!r}"
+            )
+        return _escape_comment(
+            text, invalid_chars=invalid_chars, noncharacters=noncharacters
+        )
+    else:
+        raise ValueError(f"Unsupported context: {context!r}")
+
+    lines = text.split("\n") if context == "text" else [text]
+    return "\n".join(
+        _escape(
+            i,
+            pattern=pattern,
+            ncr_style=ncr_style,
+            invalid_chars=invalid_chars,
+            xml_version=xml_version,
+            ascii_only=ascii_only,
+            noncharacters=noncharacters,
+        )
+        for i in lines
+    )
+
+
+def _with_deadline(
+    cancel: cabc.Callable[[], bool] | None, deadline: float
+) -> cabc.Callable[[], bool]:
+    """Extend ``cancel`` to also cancel after the ``deadline``."""
+
+    def cancel_or_timeout() -> bool:
+        if cancel is not None and cancel():
+            return True
+        return time.monotonic() >= deadline
+
+    return cancel_or_timeout
+
+
+def _warn(message: str, category: type[Warning] = UserWarning) -> None:
+    """Emit a warning that points at the code calling into this module."""
+    stacklevel = 1
+    frame: types.FrameType | None = sys._getframe()
+    while frame is not None and frame.f_code.co_filename == __file__:
+        frame = frame.f_back
+        stacklevel += 1
+    warnings.warn(message, category, stacklevel=stacklevel)
+
+
+def _suggest_input(tree: object) -> str:
+    """Suggest how to turn ``tree`` into something serializable."""
+    if isinstance(tree, (str, bytes, bytearray)):
+        return ", parse the XML with lxml.etree.fromstring() first"
+    if isinstance(tree, cabc.Sequence):
+        return (
+            ", serialize each element separately"
+            " or append them to a common root element"
+        )
+    return ""
+
+
+def _declare(
+    encoding: str,
+    *,
+    newline: str | None = None,
+    version: str = "1.0",
+    standalone: bool | str | None = None,
+) -> bytes:
+    if isinstance(standalone, bool):
+        standalone = ("no", "yes")[standalone]
+    elif standalone not in (None, "yes", "no"):
+        raise ValueError(f"Invalid standalone declaration: {standalone!r}")
     return b"".join(
         (
-            b'<?xml version="1.0" encoding="',
+            b'<?xml version="',
+            version.encode("ascii"),
+            b'" encoding="',
             encoding.upper().encode("ascii"),
-            b'"?>',
-            os.linesep.encode("ascii"),
+            b'"',
+            b' standalone="%s"' % standalone.encode("ascii")
+            if standalone is not None
+            else b"",
+            b"?>",
+            _linesep(newline),
         )
     )
 
 
-def _escape(string: str, *, pattern: re.Pattern[str] = P_ESCAPE_TEXT) -> str:
-    return pattern.sub(_escape_char, string)
+def _linesep(newline: str | None) -> bytes:
+    if newline is None:
+        return LINESEP
+    if newline not in ("\n", "\r\n"):
+        raise ValueError(f"Unsupported newline: {newline!r}")
+    return newline.encode("ascii")
+
+
+def _escape(
+    string: str,
+    *,
+    pattern: re.Pattern[str] = P_ESCAPE_TEXT,
+    ncr_style: t.Literal["hex", "decimal"] = "hex",
+    invalid_chars: t.Literal[
+        "error", "replace", "strip", "reference"
+    ] = "error",
+    xml_version: str = "1.0",
+    ascii_only: bool = False,
+    charset: str | None = None,
+    noncharacters: t.Literal[
+        "keep", "error", "replace", "strip", "reference"
+    ] = "keep",
+) -> str:
+    invalid = P_INVALID_CHARS_1_1 if xml_version == "1.1" else P_INVALID_CHARS
+    string = _replace_invalid(string, invalid, invalid_chars)
+    if ncr_style == "decimal":
+        escape_char = _escape_char_decimal
+    else:
+        escape_char = _escape_char
+    string = pattern.sub(escape_char, string)
+    if xml_version == "1.1":
+        string = P_RESTRICTED_CHARS_1_1.sub(escape_char, string)
+    if noncharacters != "keep" and not string.isascii():
+        if noncharacters == "reference":
+            string = P_NONCHARACTERS.sub(escape_char, string)
+        else:
+            string = _replace_invalid(string, P_NONCHARACTERS, noncharacters)
+    if ascii_only:
+        string = P_NON_ASCII.sub(escape_char, string)
+    elif charset is not None:
+        target: str = charset
+
+        def escape_unencodable(match: re.Match[str]) -> str:
+            try:
+                match.group(0).encode(target)
+            except UnicodeEncodeError:
+                return escape_char(match)
+            return match.group(0)
+
+        string = P_NON_ASCII.sub(escape_unencodable, string)
+    return string
+
+
+def _escape_comment(
+    string: str,
+    *,
+    invalid_chars: t.Literal["error", "replace", "strip", "reference"],
+    noncharacters: t.Literal[
+        "keep", "error", "replace", "strip", "reference"
+    ] = "keep",
+) -> str:
+    """Prepare the text of a comment for writing.
+
+    Character references are not recognized inside comments (nor in
+    CDATA sections), so nothing is escaped.  Characters that cannot
+    appear in a comment are handled according to ``invalid_chars``,
+    and noncharacters according to ``noncharacters``, where
+    ``"reference"`` raises an error just like ``"error"``.
+    """
+    if invalid_chars == "reference":
+        invalid_chars = "error"
+    string = _replace_invalid(string, P_INVALID_CHARS, invalid_chars)
+    if noncharacters != "keep" and not string.isascii():
+        if noncharacters == "reference":
+            noncharacters = "error"
+        string = _replace_invalid(string, P_NONCHARACTERS, noncharacters)
+    return string
+
+
+def _replace_invalid(
+    string: str,
+    pattern: re.Pattern[str],
+    policy: t.Literal["error", "replace", "strip", "reference"],
+) -> str:
+    if policy == "error":
+        if match := pattern.search(string):
+            raise _InvalidCharacter(
+                f"Invalid character {match.group(0)!r} at offset"
+                f" {match.start()}"
+            )
+    elif policy == "replace":
+        string = pattern.sub("\uFFFD", string)
+    elif policy == "strip":
+        string = pattern.sub("", string)
+    return string
 
 
 def _escape_char(
-    match: re.Match[str], *, ord_low: int = ord(" "), ord_high: int = ord("~")
+    match: re.Match[str],
+    *,
+    ord_low: int = ord(" "),
+    ord_high: int = ord("~"),
+    decimal: bool = False,
 ) -> str:
     char = match.group(0)
-    assert len(char) == 1
+    if len(char) != 1:
+        raise ValueError(f"Can only escape single characters, not {char!r}")
+    if char == "'":
+        return "&apos;"
     if ord_low <= ord(char) <= ord_high:
         return "&{};".format(html.entities.codepoint2name[ord(char)])
+    if decimal:
+        return f"&#{ord(char)};"
     return f"&#x{ord(char):X};"
 
 
-def _unmapped_attrs(
-    nsmap: cabc.Mapping[str, str], element: lxml.etree._Element
-) -> cabc.Iterator[tuple[str, str]]:
-    if element.getparent() is None:
-        parent_ns = set()
-    else:
-        parent_ns = set(element.getparent().nsmap)
+def _escape_char_decimal(match: re.Match[str]) -> str:
+    return _escape_char(match, decimal=True)
+
+
+class _CallbackError(Exception):
+    """Wraps an exception raised by a callback of the user."""
+
+    def __init__(self, error: Exception) -> None:
+        super().__init__(error)
+        self.error = error
+
+
+class _Cancelled(Exception):
+    """Raised when the ``cancel`` callback requests to stop."""
+
+
+class _OutputLimitReached(Exception):
+    """Raised when a write would exceed the output limit."""
+
+
+class _LimitedBuffer(io.BytesIO):
+    """A buffer that refuses to grow beyond ``limit`` bytes."""
+
+    def __init__(self, limit: int) -> None:
+        super().__init__()
+        self.limit = limit
+
+    def write(self, data: bytes) -> int:  # type: ignore[override]
+        if self.tell() + len(data) > self.limit:
+            raise _OutputLimitReached
+        return super().write(data)
+
+
+class _DoesNotFit(Exception):
+    """Raised when a compacted element exceeds the line length."""
+
+
+class _InvalidCharacter(ValueError):
+    """Raised when a string contains a character that XML forbids."""
+
+
+class _Serializer:
+    def __init__(
+        self,
+        buffer: _HasWrite,
+        /,
+        *,
+        encoding: str,
+        charset: str | None,
+        errors: str,
+        line_length: float | int | None,
+        indent: str,
+        linesep: bytes,
+        count_escapes: bool,
+        attr_per_line: bool,
+        attr_align: t.Literal["indent", "first"],
+        continuation_indent: int,
+        break_after_id: bool,
+        empty_elements: t.Literal["default", "self-close", "expand"],
+        expanded_tags: cabc.Container[str],
+        trailing_newline: bool,
+        trailing_linesep: bytes,
+        max_depth: int,
+        compact: bool,
+        empty_element_linebreak: bool,
+        wrap_attr_values: bool,
+        quote_char: str,
+        sort_attributes: bool,
+        attr_order: cabc.Iterable[str],
+        reorder_special_attrs: bool,
+        xmlns_position: t.Literal["after-specials", "first", "last"],
+        declaration: bytes,
+        doctype: str,
+        comments: bool,
+        drop_attributes: frozenset[str],
+        omit_empty_attributes: bool,
+        keep_empty_attributes: frozenset[str],
+        normalize_whitespace: bool,
+        verbatim_tags: frozenset[str],
+        reindent: bool,
+        normalize_text_newlines: bool,
+        max_attrs_per_line: float | int,
+        dangling_bracket: bool,
+        space_before_self_close: bool,
+        keep_id_on_tag_line: bool,
+        banner: str | None,
+        base_indent: int,
+        blank_line_between_root_children: bool,
+        minify: bool,
+        front_attributes: tuple[str, ...],
+        sort_children: dict[str, str],
+        early_namespaces: cabc.Iterable[str],
+        hoist_namespaces: bool,
+        prune_unused_namespaces: bool,
+        prefix_map: dict[str, str],
+        prefix_choice: str | dict[str, int],
+        xmlns_sort: t.Literal["alias", "uri"] | None,
+        declare_missing_namespaces: bool,
+        anchor_schema_location: bool,
+        validate_names: bool,
+        check_namespace_conflicts: bool | t.Literal["warn"],
+        ncr_style: t.Literal["hex", "decimal"],
+        invalid_chars: t.Literal["error", "replace", "strip", "reference"],
+        xml_version: str,
+        ascii_only: bool,
+        fix_comments: bool,
+        cdata_tags: frozenset[str],
+        normalize_unicode: str | None,
+        noncharacters: t.Literal[
+            "keep", "error", "replace", "strip", "reference"
+        ],
+        compat: str | None,
+        on_dropped_content: t.Literal["ignore", "warn", "error"],
+        problems: list[Problem] | None,
+        lint: bool,
+        lint_max_attribute_length: int,
+        lint_id_pattern: str | re.Pattern[str],
+        trace: cabc.Callable[[tuple[t.Any, ...]], object] | None,
+        cancel: cabc.Callable[[], bool] | None,
+        cancel_interval: int,
+    ) -> None:
+        self.buffer = buffer
+        self.encoding = encoding
+        self.charset = charset
+        self.errors = errors
+        self.wrap = bool(line_length)
+        self.line_length = line_length or math.inf
+        self.indent = indent.encode("ascii")
+        self.linesep = linesep
+        self.count_escapes = count_escapes
+        self.attr_per_line = attr_per_line
+        self.attr_align = attr_align
+        self.continuation_indent = continuation_indent
+        self.break_after_id = break_after_id
+        self.empty_elements = empty_elements
+        self.expanded_tags = expanded_tags
+        self.trailing_newline = trailing_newline
+        self.trailing_linesep = trailing_linesep
+        self.max_depth = max_depth
+        self.compact = compact
+        self.empty_element_linebreak = empty_element_linebreak
+        self.wrap_attr_values = wrap_attr_values
+        self.sort_attributes = sort_attributes
+        self.attr_order = {name: i for i, name in enumerate(attr_order)}
+        self.reorder_special_attrs = reorder_special_attrs
+        self.xmlns_position = xmlns_position
+        self.declaration = declaration
+        self.doctype = doctype
+        self.comments = comments
+        self.drop_attributes = drop_attributes
+        self.omit_empty_attributes = omit_empty_attributes
+        self.keep_empty_attributes = keep_empty_attributes
+        self.normalize_whitespace = normalize_whitespace
+        self.verbatim_tags = verbatim_tags
+        self.reindent = reindent
+        self.normalize_text_newlines = normalize_text_newlines
+        self.max_attrs_per_line = max_attrs_per_line
+        self.dangling_bracket = dangling_bracket
+        self.space_before_self_close = space_before_self_close
+        self.keep_id_on_tag_line = keep_id_on_tag_line
+        self.banner = banner
+        self.base_indent = base_indent
+        self.blank_line_between_root_children = (
+            blank_line_between_root_children
+        )
+        self.minify = minify
+        self.front_attributes = front_attributes
+        self.sort_children = sort_children
+        self.early_namespaces = {
+            uri: i for i, uri in enumerate(early_namespaces)
+        }
+        self.hoist_namespaces = hoist_namespaces
+        self.prune_unused_namespaces = prune_unused_namespaces
+        self.prefix_map = prefix_map
+        self.prefix_choice = prefix_choice
+        self.xmlns_sort = xmlns_sort
+        self.declare_missing_namespaces = declare_missing_namespaces
+        self.anchor_schema_location = anchor_schema_location
+        self.validate_names = validate_names
+        self.check_namespace_conflicts = check_namespace_conflicts
+        self.ncr_style = ncr_style
+        self.invalid_chars = invalid_chars
+        self.xml_version = xml_version
+        self.ascii_only = ascii_only
+        self.fix_comments = fix_comments
+        self.cdata_tags = cdata_tags
+        self.normalize_unicode = normalize_unicode
+        self.noncharacters = noncharacters
+        self.on_dropped_content = on_dropped_content
+        self.problems = problems
+        self.lint = lint
+        self.lint_max_attribute_length = lint_max_attribute_length
+        self.lint_id_pattern = re.compile(lint_id_pattern)
+        self.trace = trace
+        self.cancel = cancel
+        self.cancel_interval = cancel_interval
+        # Counts the started elements, attributes and lines of text to
+        # poll ``cancel`` periodically.
+        # Like the breadcrumb, it is shared with the scratch copies.
+        self.ticks = itertools.count(1)
+        # The problems found so far, with the output offset at which
+        # each was found.  Their positions are resolved in one pass
+        # over the output by ``report_problems``.
+        self.found: list[tuple[int, Problem]] = []
+        self.hoisted: dict[str | None, str] | None = None
+        self.root: lxml.etree._Element | None = None
+        # The serializer whose output a scratch copy continues.
+        self.enclosing: _Serializer | None = None
+        self.quote = quote_char.encode("ascii")
+        if quote_char == "'":
+            self.attr_pattern = P_ESCAPE_SINGLE_QUOTED
+        elif compat == "emf":
+            self.attr_pattern = P_ESCAPE_TEXT_EMF
+        else:
+            self.attr_pattern = P_ESCAPE_TEXT
+        if compat == "emf":
+            self.text_pattern = P_ESCAPE_ELEMENT_TEXT_EMF
+        else:
+            self.text_pattern = P_ESCAPE_ELEMENT_TEXT
+        self.inline = False
+        self.inline_limit: float | int = math.inf
+        self.__indent_widths: dict[int, int] = {}
+        self.__used_namespaces: dict[lxml.etree._Element, set[str]] = {}
+        self.__valid_prefixes: set[str] = set()
+        # The attributes that are moved to the front, mapped to their
+        # position there.
+        self.__front_ranks: dict[str, int] = {}
+        self.__anchor_ranks: dict[str, int] = {}
+        if reorder_special_attrs:
+            self.__front_ranks = {n: i for i, n in enumerate(front_attributes)}
+        if anchor_schema_location:
+            self.__anchor_ranks = {
+                n: i for i, n in enumerate(SCHEMA_LOCATION_ATTRIBUTES)
+            }
+        self.__namespace_stems: dict[
+            str, tuple[str, lxml.etree._Element]
+        ] = {}
+        # The node that is currently being serialized, which is used to
+        # locate errors.  The list is shared with the scratch copies
+        # made by compacted().
+        self.breadcrumb: list[lxml.etree._Element | None] = [None]
+
+    def serialize_tree(
+        self, tree: lxml.etree._Element | lxml.etree._ElementTree
+    ) -> None:
+        root: lxml.etree._Element
+        preceding_siblings: cabc.Sequence[lxml.etree._Comment]
+        following_siblings: cabc.Sequence[lxml.etree._Comment]
+        if isinstance(tree, lxml.etree._ElementTree):
+            root = tree.getroot()
+            preceding_siblings = list(root.itersiblings(preceding=True))
+            preceding_siblings.reverse()
+            following_siblings = list(root.itersiblings())
+        else:
+            root = tree
+            preceding_siblings = ()
+            following_siblings = ()
+            if self.on_dropped_content != "ignore":
+                self.check_dropped_content(root)
+        if self.lint:
+            self.lint_tree(root)
+
+        if self.hoist_namespaces:
+            self.hoisted = self.namespaces(root, _declared_namespaces(root))
+            self.root = root
+
+        self.buffer.write(self.declaration)
+        if self.banner is not None:
+            for i, line in enumerate(self.banner.split("\n")):
+                self.buffer.write(self.linesep if i else b"<!--")
+                self.serialize_text(line, pos=0, comment=True)
+            self.buffer.write(b"-->")
+            if not self.minify:
+                self.buffer.write(self.linesep)
+        if self.doctype:
+            self.buffer.write(self.doctype.encode(self.encoding, self.errors))
+            if not self.minify:
+                self.buffer.write(self.linesep)
+        if not self.comments:
+            preceding_siblings = following_siblings = ()
+
+        pos = 0
+        if not preceding_siblings:
+            self.buffer.write(self.indent * self.base_indent)
+        for i in preceding_siblings:
+            pos = self.serialize_comment(i, pos=pos, indent=0)
+
+        pos = self.serialize_element(root, 0, pos=pos)
+        if tail := self.significant(root.tail):
+            pos = self.serialize_text(tail, pos=pos, multiline=True)
+
+        for i, comment in enumerate(following_siblings, start=1):
+            pos = self.serialize_comment(
+                comment,
+                pos=pos,
+                indent=0,
+                linebreak_after=(
+                    self.trailing_newline or i < len(following_siblings)
+                ),
+            )
 
-    attribs = dict(element.items())
-    try:
-        yield (
-            _unmap_namespace(nsmap, "xmi:version"),
-            _escape(attribs.pop("{http://www.omg.org/XMI}version")),
+        if self.trailing_newline:
+            self.buffer.write(self.trailing_linesep)
+
+    def check_dropped_content(self, root: lxml.etree._Element) -> None:
+        """Report the nodes next to ``root`` that are not serialized.
+
+        Comments and processing instructions next to the root element
+        are only written if the whole ``_ElementTree`` is serialized.
+        """
+        if root.getparent() is not None:
+            return
+        dropped = list(root.itersiblings(preceding=True))
+        dropped.reverse()
+        dropped.extend(root.itersiblings())
+        if not dropped:
+            return
+
+        message = (
+            f"Dropping {len(dropped)} node(s) next to the root element,"
+            " pass the ElementTree to serialize them: "
+            + ", ".join(map(repr, dropped))
         )
-    except KeyError:
-        pass
+        if self.on_dropped_content == "warn":
+            _warn(message)
+        else:
+            raise ValueError(message)
+
+    def lint_tree(self, root: lxml.etree._Element) -> None:
+        """Warn about suspicious content in the tree below ``root``.
+
+        Such content can be serialized, but was most likely produced by
+        a bug in the code that built the tree.
+        """
+        for element in root.iter():
+            if not isinstance(element.tag, str):
+                continue
+            for name in itertools.chain((element.tag,), element.keys()):
+                if P_CONTROL_CHARS.search(name):
+                    _warn(
+                        f"Control character in name {name!r}"
+                        f" at {_describe_nearest(element)}",
+                        LintWarning,
+                    )
+            for name, value in element.items():
+                if (
+                    isinstance(value, str)
+                    and len(value) > self.lint_max_attribute_length
+                ):
+                    _warn(
+                        f"Attribute {name!r} at {_describe_nearest(element)}"
+                        f" is {len(value)} characters long",
+                        LintWarning,
+                    )
+            xmi_id = element.get(f"{{{NS_XMI}}}id")
+            if (
+                isinstance(xmi_id, str)
+                and not self.lint_id_pattern.fullmatch(xmi_id)
+            ):
+                _warn(
+                    f"The xmi:id {xmi_id!r} at {_describe_nearest(element)}"
+                    " is not shaped like a UUID",
+                    LintWarning,
+                )
+
+    def linebreak(self, indent: int, *, align: int = 0) -> int:
+        if self.minify:
+            return 0
+        self.buffer.write(self.linesep)
+        self.buffer.write(self.indent * (self.base_indent + indent))
+        self.buffer.write(b" " * align)
+        return self.indent_width(indent) + align
+
+    def indent_width(self, indent: int) -> int:
+        try:
+            return self.__indent_widths[indent]
+        except KeyError:
+            pass
+        width = len((self.indent * indent).expandtabs(TAB_WIDTH))
+        self.__indent_widths[indent] = width
+        return width
+
+    def serialize_comment(
+        self,
+        comment: lxml.etree._Comment,
+        /,
+        *,
+        pos: int,
+        indent: int,
+        linebreak_after: bool = True,
+    ) -> int:
+        self.breadcrumb[0] = comment
+        if not isinstance(comment, lxml.etree._Comment):
+            raise TypeError(
+                f"Cannot serialize {comment!r} next to the root element,"
+                " only comments are supported there"
+            )
 
-    for attr, value in element.nsmap.items():
-        if attr in parent_ns:
-            continue
-        yield (f"xmlns:{attr}", value)
+        pos = self.linebreak(indent)
+        pos = self.serialize_comment_body(comment, pos=pos)
+
+        if tail := self.significant(comment.tail):
+            pos = self.serialize_text(tail, pos=pos)
+        elif linebreak_after:
+            pos = self.linebreak(indent)
+        return pos
+
+    def serialize_comment_body(
+        self, comment: lxml.etree._Comment, /, *, pos: int, indent: int = 0
+    ) -> int:
+        """Serialize the ``<!--...-->`` part of a comment.
+
+        Continuation lines of a multi-line comment are indented by
+        ``indent`` levels.  Any indentation that they have in common is
+        removed first, so that re-serializing a comment does not indent
+        it any further.
+
+        XML does not allow ``--`` inside a comment, nor a ``-`` at its
+        end.  Such comments are either fixed by inserting spaces, or
+        rejected with a ``ValueError``.
+        """
+        self.breadcrumb[0] = comment
+        text = self.normalize(comment.text or "")
+        if "--" in text or text.endswith("-"):
+            if not self.fix_comments:
+                self.recover(
+                    ValueError(
+                        "Comment may not contain '--' or end with '-':"
+                        f" {text!r}"
+                    ),
+                    comment,
+                    "comment",
+                )
+            while "--" in text:
+                text = text.replace("--", "- -")
+            if text.endswith("-"):
+                text += " "
+
+        lines = self.splitlines(text)
+        if indent and len(lines) > 1:
+            lines[1:] = textwrap.dedent("\n".join(lines[1:])).split("\n")
+
+        self.buffer.write(b"<!--")
+        pos += 4
+        for i, line in enumerate(lines):
+            if i and self.minify:
+                self.buffer.write(self.linesep)
+                pos = 0
+            elif i:
+                pos = self.linebreak(indent)
+            pos = self.serialize_text(line, pos=pos, comment=True)
+        self.buffer.write(b"-->")
+        return pos + 3
+
+    def serialize_element(
+        self,
+        element: lxml.etree._Element,
+        indent: int,
+        /,
+        *,
+        pos: int,
+        depth: int = 0,
+        verbatim: bool = False,
+    ) -> int:
+        self.breadcrumb[0] = element
+        if not isinstance(element.tag, str):
+            # lxml uses the factory functions as tag of comments, PIs
+            # and entities.  Comments are handled by the parent.
+            if depth == 0:
+                raise TypeError(
+                    f"Cannot serialize {_describe_node(element)},"
+                    " the root must be an element"
+                )
+            self.recover(
+                TypeError(
+                    f"Cannot serialize {_describe_node(element)},"
+                    " processing instructions and entities are not"
+                    " supported"
+                ),
+                element,
+                "node",
+            )
+            return pos
+        for what, value in (("text", element.text), ("tail", element.tail)):
+            if value is not None and not isinstance(value, str):
+                self.recover(
+                    TypeError(
+                        f"The {what} of {_describe_nearest(element)} must"
+                        f" be a str, not {type(value).__name__}"
+                    ),
+                    element,
+                    "type",
+                )
+        if depth >= self.max_depth:
+            raise RecursionError(
+                f"Maximum nesting depth of {self.max_depth} exceeded"
+                f" at {_describe(element)}"
+            )
+        if self.trace is not None:
+            self.callback(self.trace, ("start_element", element.tag, indent))
+        self.check_cancelled()
+        space = element.get(f"{{{NS_XML}}}space")
+        if element.tag in self.verbatim_tags or space == "preserve":
+            verbatim = True
+        elif space == "default":
+            verbatim = any(
+                i.tag in self.verbatim_tags for i in element.iterancestors()
+            )
 
-    for attr, value in attribs.items():
-        yield (_unmap_namespace(nsmap, attr), _escape(value))
+        layout = element.get(FORMAT_MARKER)
+        if layout not in (None, "compact", "expand"):
+            raise ValueError(
+                f"Unknown format marker {layout!r} on {_describe(element)}"
+            )
 
+        if self.inline or verbatim or layout == "expand":
+            compacted = None
+        elif layout == "compact":
+            compacted = self.compacted(
+                element, indent, pos=pos, depth=depth, limit=math.inf
+            )
+        elif self.compact and len(element) > 0:
+            compacted = self.compacted(element, indent, pos=pos, depth=depth)
+        else:
+            compacted = None
+        if compacted is not None:
+            data, pos = compacted
+            self.buffer.write(data)
+            return pos
+
+        namespaces = self.namespaces(element)
+        nsmap = self.prefixes(namespaces)
+        self.buffer.write(b"<")
+        tagname = _unmap_namespace(
+            nsmap,
+            element.tag,
+            default=namespaces.get(None),
+            element=element,
+        )
+        tag = tagname.encode(self.encoding, self.errors)
+        self.buffer.write(tag)
+
+        pos += 1 + len(tagname)
+        if self.attr_align == "first" and pos + 1 <= self.line_length:
+            # Align with the column of the first attribute, which may be
+            # further right than the indentation after inline text.
+            attr_indent = indent if pos >= self.indent_width(indent) else 0
+            attr_align = pos + 1 - self.indent_width(attr_indent)
+        else:
+            attr_indent = indent + self.continuation_indent
+            attr_align = 0
+        attributes = self.attributes(nsmap, element)
+        unwrapped = 0
+        if self.keep_id_on_tag_line:
+            id_names = {"id"}
+            if NS_XMI in nsmap:
+                id_names.add(f"{nsmap[NS_XMI]}:id")
+            unwrapped = next(
+                (i for i, (a, _) in enumerate(attributes, 1) if a in id_names),
+                0,
+            )
 
-def _serialize_comment(
-    buffer: _HasWrite,
-    comment: lxml.etree._Comment,
-    /,
-    *,
-    encoding: str,
-    errors: str,
-    pos: int,
-    indent: int,
-) -> int:
-    assert isinstance(comment, lxml.etree._Comment)
-
-    buffer.write(LINESEP)
-    buffer.write(INDENT * indent)
-    buffer.write(b"<!--")
-    pos = _serialize_text(
-        buffer,
-        comment.text,
-        encoding=encoding,
-        errors=errors,
-        pos=len(INDENT) * indent,
-        pattern=re.compile(r">"),
-    )
-    buffer.write(b"-->")
+        force_break = False
+        wrapped = False
+        attrs_on_line = 0
+        for i, (attr, value) in enumerate(attributes):
+            self.check_cancelled()
+            if verbatim or i < unwrapped:
+                self.buffer.write(b" ")
+                pos += 1
+            elif (
+                self.attr_per_line
+                or pos > self.line_length
+                or force_break
+                or attrs_on_line >= self.max_attrs_per_line
+            ):
+                if self.trace is not None:
+                    self.callback(self.trace, ("wrap", attr, pos))
+                pos = self.linebreak(attr_indent, align=attr_align)
+                force_break = False
+                wrapped = True
+                attrs_on_line = 0
+            else:
+                self.buffer.write(b" ")
+                pos += 1
+            attrs_on_line += 1
+
+            self.buffer.write(attr.encode(self.encoding, self.errors))
+            self.buffer.write(b"=" + self.quote)
+            start = self.buffer.tell()
+            try:
+                pos = self.serialize_attr_value(
+                    value, pos=pos + len(attr) + 2, verbatim=verbatim
+                )
+            except (_InvalidCharacter, UnicodeEncodeError) as err:
+                self.discard(start)
+                self.recover(
+                    ValueError(
+                        f"{err} in attribute {attr!r}"
+                        f" of {_describe_nearest(element)}"
+                    ),
+                    element,
+                    "character",
+                    attribute=attr,
+                )
+            self.buffer.write(self.quote)
+            pos += 1
+            if self.inline and pos > self.inline_limit:
+                raise _DoesNotFit
+
+            if (
+                self.break_after_id
+                and self.reorder_special_attrs
+                and self.wrap
+                and element.getparent() is None
+                and attr == "id"
+            ):
+                force_break = True
+
+        if wrapped and self.dangling_bracket:
+            pos = self.linebreak(indent)
+        if (
+            self.significant(element.text, verbatim=verbatim) is None
+            and len(element) == 0
+            and not self.is_expanded(element)
+        ):
+            if self.space_before_self_close:
+                self.buffer.write(b" ")
+                pos += 1
+            self.buffer.write(b"/>")
+            return pos + 2
+        self.buffer.write(b">")
+
+        if text := self.significant(element.text, verbatim=verbatim):
+            start = self.buffer.tell()
+            try:
+                if element.tag in self.cdata_tags:
+                    pos = self.serialize_cdata(text, pos=pos)
+                else:
+                    pos = self.serialize_text(text, pos=pos, multiline=True)
+            except (_InvalidCharacter, UnicodeEncodeError) as err:
+                self.discard(start)
+                self.recover(
+                    ValueError(
+                        f"{err} in the text of {_describe_nearest(element)}"
+                    ),
+                    element,
+                    "character",
+                )
+                text_content = False
+            else:
+                text_content = True
+        else:
+            text_content = False
 
-    if (comment.tail or "").strip():
-        pos = _serialize_text(
-            buffer, comment.tail, encoding=encoding, errors=errors, pos=pos
+        has_children = previous_comment = False
+        blank_lines = self.blank_line_between_root_children and depth == 0
+        for child in self.children(element):
+            is_comment = isinstance(child, lxml.etree._Comment)
+            if self.inline and is_comment:
+                raise _DoesNotFit
+            if is_comment and not self.comments:
+                pass
+            else:
+                if not text_content and not self.inline and not verbatim:
+                    if blank_lines and has_children and not previous_comment:
+                        self.buffer.write(self.linesep)
+                    pos = self.linebreak(indent + 1)
+                if is_comment:
+                    pos = self.serialize_comment_body(
+                        child, pos=pos, indent=indent + 1
+                    )
+                else:
+                    pos = self.serialize_element(
+                        child,
+                        indent + 1,
+                        pos=pos,
+                        depth=depth + 1,
+                        verbatim=verbatim,
+                    )
+                has_children = True
+                previous_comment = is_comment
+                text_content = False
+
+            if tail := self.significant(child.tail, verbatim=verbatim):
+                start = self.buffer.tell()
+                try:
+                    pos = self.serialize_text(tail, pos=pos, multiline=True)
+                except (_InvalidCharacter, UnicodeEncodeError) as err:
+                    self.discard(start)
+                    self.recover(
+                        ValueError(
+                            f"{err} in the text of"
+                            f" {_describe_nearest(element)}"
+                        ),
+                        element,
+                        "character",
+                    )
+                else:
+                    text_content = True
+
+        self.breadcrumb[0] = element
+        if self.inline or verbatim:
+            pass
+        elif has_children and not text_content:
+            pos = self.linebreak(indent)
+        elif (
+            self.empty_element_linebreak
+            and len(element) == 0
+            and self.significant(element.text) is None
+        ):
+            pos = self.linebreak(indent)
+
+        self.buffer.write(b"</")
+        self.buffer.write(tag)
+        self.buffer.write(b">")
+        pos += len(tagname) + 3
+        if self.inline and pos > self.inline_limit:
+            raise _DoesNotFit
+        return pos
+
+    def children(
+        self, element: lxml.etree._Element
+    ) -> cabc.Iterable[lxml.etree._Element]:
+        try:
+            key_attr = self.sort_children[element.tag]
+        except KeyError:
+            return element
+
+        # Comments are moved together with the element that follows
+        # them, and those after the last element stay at the end.
+        groups: list[list[lxml.etree._Element]] = [[]]
+        for child in element:
+            groups[-1].append(child)
+            if isinstance(child.tag, str):
+                groups.append([])
+        trailing = groups.pop()
+
+        def key(group: list[lxml.etree._Element]) -> tuple[bool, str]:
+            value = group[-1].get(key_attr)
+            return (value is None, value or "")
+
+        groups.sort(key=key)
+        groups.append(trailing)
+        return itertools.chain.from_iterable(groups)
+
+    def used_namespaces(self, element: lxml.etree._Element) -> set[str]:
+        """Find the namespaces that are used within ``element``'s subtree.
+
+        Besides the namespaces of tags and attributes, this includes
+        namespaces that are referenced by the prefix of an
+        ``xsi:type`` or ``xmi:type`` value.
+        """
+        try:
+            return self.__used_namespaces[element]
+        except KeyError:
+            pass
+
+        used: set[str] = set()
+        for child in element.iter():
+            if not isinstance(child.tag, str):
+                continue
+            for name in itertools.chain((child.tag,), child.keys()):
+                if name.startswith("{"):
+                    used.add(name[1 : name.index("}")])
+            for name in (f"{{{NS_XSI}}}type", f"{{{NS_XMI}}}type"):
+                prefix, sep, _ = child.get(name, "").partition(":")
+                if sep and (uri := child.nsmap.get(prefix)):
+                    used.add(uri)
+        self.__used_namespaces[element] = used
+        return used
+
+    def uses_exs_namespace(self, element: lxml.etree._Element) -> bool:
+        """Check if ``element``'s subtree writes anything in :data:`NS_EXS`.
+
+        Format markers are never written, so the namespace only needs
+        to be declared if it is used for other tags or attributes.
+        """
+        for child in element.iter():
+            if not isinstance(child.tag, str):
+                continue
+            for name in itertools.chain((child.tag,), child.keys()):
+                if name.startswith(f"{{{NS_EXS}}}") and name != FORMAT_MARKER:
+                    return True
+        return False
+
+    def namespaces(
+        self,
+        element: lxml.etree._Element,
+        namespaces: cabc.Mapping[str | None, str] | None = None,
+    ) -> cabc.Mapping[str | None, str]:
+        """Find the prefixes that are in scope at ``element``.
+
+        Prefixes are rewritten according to the ``prefix_map``.  If
+        ``namespaces`` is given, it is rewritten instead of the
+        element's own nsmap.  If ``declare_missing_namespaces`` is
+        set, prefixes are generated for namespaces that the element
+        uses without them being in scope.
+
+        Raises
+        ------
+        ValueError
+            If a preferred prefix is already bound to another namespace.
+        """
+        if namespaces is None:
+            namespaces = element.nsmap
+        if self.prefix_map:
+            rewritten: dict[str | None, str] = {}
+            for prefix, uri in namespaces.items():
+                prefix = self.prefix_map.get(uri, prefix)
+                if (other := rewritten.setdefault(prefix, uri)) != uri:
+                    raise ValueError(
+                        f"Cannot bind prefix {prefix!r} to {uri!r},"
+                        f" it is already bound to {other!r}"
+                        f" at {_describe_nearest(element)}"
+                    )
+            namespaces = rewritten
+        if not self.declare_missing_namespaces:
+            return namespaces
+
+        declared = dict(namespaces)
+        names = itertools.chain((element.tag,), element.keys())
+        for i, name in enumerate(names):
+            if not name.startswith("{"):
+                continue
+            uri = name[1 : name.index("}")]
+            if uri == NS_XML or (i == 0 and declared.get(None) == uri):
+                continue
+            if uri in (v for k, v in declared.items() if k is not None):
+                continue
+            n = 0
+            while f"ns{n}" in declared:
+                n += 1
+            declared[f"ns{n}"] = uri
+        return declared
+
+    def prefixes(
+        self, namespaces: cabc.Mapping[str | None, str]
+    ) -> dict[str, str]:
+        """Choose the prefix to use for each namespace URI.
+
+        If several prefixes are bound to the same URI, the
+        ``prefix_choice`` decides which one is used.
+        """
+        return _choose_prefixes(namespaces, self.prefix_choice)
+
+    def validate_prefix(
+        self, prefix: str | None, element: lxml.etree._Element
+    ) -> None:
+        """Check that a declared prefix is a valid NCName."""
+        if prefix is None or prefix in self.__valid_prefixes:
+            return
+        if not P_NCNAME.fullmatch(prefix):
+            raise ValueError(
+                f"Invalid namespace prefix {prefix!r}"
+                f" declared at {_describe_nearest(element)}"
+            )
+        self.__valid_prefixes.add(prefix)
+
+    def check_namespace_conflict(
+        self, uri: str, element: lxml.etree._Element
+    ) -> None:
+        """Check ``uri`` against the namespaces declared so far.
+
+        Two namespaces conflict if they only differ in a trailing
+        version segment or slash, like different versions of the same
+        Capella metamodel.
+        """
+        stem = P_NAMESPACE_VERSION.sub("", uri)
+        other, declarer = self.__namespace_stems.setdefault(
+            stem, (uri, element)
         )
-    else:
-        buffer.write(LINESEP)
-        buffer.write(INDENT * indent)
-        pos = len(INDENT) * indent
-    return pos
+        if other == uri:
+            return
 
-
-def _serialize_element(
-    buffer: _HasWrite,
-    element: lxml.etree._Element,
-    indent: int,
-    *,
-    encoding: str,
-    errors: str,
-    pos: int = 0,
-    line_length: float | int,
-) -> int:
-    assert isinstance(element, lxml.etree._Element)
-    nsmap = dict((v, k) for k, v in element.nsmap.items())
-    buffer.write(b"<")
-    tag = _unmap_namespace(nsmap, element.tag).encode(encoding, errors)
-    buffer.write(tag)
-
-    pos += 1 + len(tag)
-    attr_indent = INDENT * (indent + 2)
-    force_break = False
-    for attr, value in _unmapped_attrs(nsmap, element):
-        if pos > line_length or force_break:
-            buffer.write(LINESEP)
-            buffer.write(attr_indent)
-            pos = len(attr_indent)
-            force_break = False
+        message = (
+            f"Namespace {uri!r} at {_describe_nearest(element)}"
+            f" conflicts with {other!r} at {_describe_nearest(declarer)}"
+        )
+        if self.check_namespace_conflicts == "warn":
+            _warn(message)
         else:
-            buffer.write(b" ")
-            pos += 1
+            raise ValueError(message)
+
+    def attributes(
+        self, nsmap: cabc.Mapping[str, str], element: lxml.etree._Element
+    ) -> list[tuple[str, str]]:
+        namespaces: cabc.Mapping[str | None, str]
+        parent = element.getparent()
+        if self.hoisted is not None and element is self.root:
+            namespaces = self.hoisted
+            parent_ns = {}
+        elif self.hoisted is not None:
+            namespaces = self.namespaces(element)
+            parent_ns = self.hoisted
+        elif parent is None:
+            namespaces = self.namespaces(element)
+            parent_ns = {}
+        else:
+            namespaces = self.namespaces(element)
+            parent_ns = self.namespaces(parent)
+
+        specials: list[tuple[int, str, str]] = []
+        anchored: list[tuple[int, str, str]] = []
+        unmapped: list[tuple[str, str]] = []
+        for attr, value in element.items():
+            if (
+                attr in self.drop_attributes
+                or attr == FORMAT_MARKER
+                or not (
+                    value
+                    or not self.omit_empty_attributes
+                    or attr in self.keep_empty_attributes
+                )
+            ):
+                continue
+            if not isinstance(value, str):
+                self.recover(
+                    TypeError(
+                        f"The value of attribute {attr!r}"
+                        f" of {_describe_nearest(element)} must be a str,"
+                        f" not {type(value).__name__}"
+                    ),
+                    element,
+                    "type",
+                )
+                continue
+            value = self.normalize(value)
+            if self.prefix_map and attr in TYPE_ATTRIBUTES:
+                prefix, sep, local = value.partition(":")
+                uri = element.nsmap.get(prefix)
+                if sep and uri in self.prefix_map:
+                    value = f"{self.prefix_map[uri]}:{local}"
+
+            if (rank := self.__front_ranks.get(attr)) is not None:
+                specials.append((rank, attr, value))
+            elif (rank := self.__anchor_ranks.get(attr)) is not None:
+                anchored.append((rank, attr, value))
+            else:
+                try:
+                    name = _unmap_namespace(nsmap, attr, element=element)
+                except (TypeError, ValueError) as err:
+                    self.recover(err, element, "name", attribute=attr)
+                else:
+                    unmapped.append((name, value))
+        special_attrs = [
+            (_unmap_namespace(nsmap, attr, element=element), value)
+            for _, attr, value in sorted(specials)
+        ]
+        anchored_attrs = [
+            (_unmap_namespace(nsmap, attr, element=element), value)
+            for _, attr, value in sorted(anchored)
+        ]
+
+        xmlns = [
+            (f"xmlns:{attr}" if attr else "xmlns", value)
+            for attr, value in namespaces.items()
+            if parent_ns.get(attr) != value
+            and (value != NS_EXS or self.uses_exs_namespace(element))
+        ]
+        if self.validate_names:
+            for prefix in namespaces:
+                self.validate_prefix(prefix, element)
+        if xmlns and self.prune_unused_namespaces:
+            used = self.used_namespaces(element)
+            xmlns = [i for i in xmlns if i[1] in used]
+        if self.check_namespace_conflicts:
+            for _, uri in xmlns:
+                self.check_namespace_conflict(uri, element)
+        if self.xmlns_sort == "alias":
+            xmlns.sort(key=lambda i: i[0])
+        elif self.xmlns_sort == "uri":
+            xmlns.sort(key=lambda i: i[1])
+        if self.early_namespaces:
+            unprioritized = len(self.early_namespaces)
+            xmlns.sort(
+                key=lambda i: self.early_namespaces.get(i[1], unprioritized)
+            )
 
-        buffer.write(attr.encode(encoding, errors))
-        buffer.write(b'="')
-        buffer.write(value.encode(encoding, errors))
-        buffer.write(b'"')
-        pos += len(attr) + len(value) + 3
+        if self.sort_attributes:
+            unmapped.sort(key=lambda i: (":" in i[0], i[0]))
+        if self.attr_order:
+            unprioritized = len(self.attr_order)
+            unmapped.sort(
+                key=lambda i: self.attr_order.get(i[0], unprioritized)
+            )
 
-        if element.getparent() is None and attr == "id":
-            force_break = True
+        if self.xmlns_position == "first":
+            return xmlns + anchored_attrs + special_attrs + unmapped
+        if self.xmlns_position == "last":
+            return special_attrs + unmapped + xmlns + anchored_attrs
+        return special_attrs + xmlns + anchored_attrs + unmapped
+
+    def serialize_attr_value(
+        self, value: str, /, *, pos: int, verbatim: bool = False
+    ) -> int:
+        escaped = _escape(
+            value,
+            pattern=self.attr_pattern,
+            ncr_style=self.ncr_style,
+            invalid_chars=self.invalid_chars,
+            xml_version=self.xml_version,
+            ascii_only=self.ascii_only,
+            charset=self.charset,
+            noncharacters=self.noncharacters,
+        )
+        if not self.wrap_attr_values or not self.wrap or verbatim:
+            self.buffer.write(escaped.encode(self.encoding, self.errors))
+            return pos + self.width(value, escaped)
+
+        # A line break in an attribute value is normalized to a single
+        # space by XML parsers.  Replacing exactly one existing space
+        # with a line break therefore keeps the value intact, as long as
+        # the continuation line is not indented.
+        raw_words = value.split(" ")
+        escaped_words = escaped.split(" ")
+        for i, (raw, word) in enumerate(zip(raw_words, escaped_words)):
+            width = self.width(raw, word)
+            if i and pos + 1 + width > self.line_length:
+                self.buffer.write(self.linesep)
+                pos = 0
+            elif i:
+                self.buffer.write(b" ")
+                pos += 1
+            self.buffer.write(word.encode(self.encoding, self.errors))
+            pos += width
+        return pos
+
+    def compacted(
+        self,
+        element: lxml.etree._Element,
+        indent: int,
+        /,
+        *,
+        pos: int,
+        depth: int,
+        limit: float | int | None = None,
+    ) -> tuple[bytes, int] | None:
+        """Serialize ``element`` on a single line, if it fits.
+
+        The line may be at most ``limit`` columns long, which defaults
+        to the configured line length.
+
+        Returns
+        -------
+        tuple[bytes, int] | None
+            The serialized element and the position after it, or None
+            if it cannot be serialized within the line length.
+        """
+        scratch = copy.copy(self)
+        scratch.buffer = io.BytesIO()
+        scratch.enclosing = self
+        scratch.inline = True
+        scratch.inline_limit = self.line_length if limit is None else limit
+        scratch.line_length = math.inf
+        scratch.wrap = False
+        scratch.attr_per_line = False
+        scratch.max_attrs_per_line = math.inf
+        scratch.trace = None
+        recorded = len(self.found)
+        try:
+            pos = scratch.serialize_element(
+                element, indent, pos=pos, depth=depth
+            )
+        except _DoesNotFit:
+            del self.found[recorded:]
+            if self.trace is not None:
+                self.callback(self.trace, ("compact", element.tag, False))
+            return None
+        if self.trace is not None:
+            self.callback(self.trace, ("compact", element.tag, True))
+        return scratch.buffer.getvalue(), pos
+
+    def check_cancelled(self) -> None:
+        """Poll ``cancel`` if the ``cancel_interval`` has passed.
+
+        Started elements, attributes and lines of text are counted
+        towards the interval, so that huge elements are cancelled
+        promptly as well.
+        """
+        if (
+            self.cancel is not None
+            and next(self.ticks) % self.cancel_interval == 0
+            and self.callback(self.cancel)
+        ):
+            raise _Cancelled
+
+    def callback(
+        self, function: cabc.Callable[..., t.Any], /, *args: t.Any
+    ) -> t.Any:
+        """Call ``function``, which was passed in by the user.
+
+        Exceptions raised by it are passed on to the caller of
+        :func:`serialize` unchanged, instead of being treated as errors
+        of the serializer.
+        """
+        try:
+            return function(*args)
+        except Exception as err:
+            raise _CallbackError(err) from err
+
+    def offset(self) -> int:
+        """Return the output offset, including that of enclosing copies."""
+        offset = self.buffer.tell()
+        if self.enclosing is not None:
+            offset += self.enclosing.offset()
+        return offset
+
+    def discard(self, start: int) -> None:
+        """Drop everything written to the buffer after ``start``.
+
+        This removes the partially written value after an error, so
+        that a recovered problem leaves no half-escaped content behind.
+        """
+        self.buffer.seek(start)
+        self.buffer.truncate()
+
+    def recover(
+        self,
+        err: Exception,
+        node: lxml.etree._Element,
+        category: str,
+        *,
+        attribute: str | None = None,
+    ) -> None:
+        """Record ``err`` as a problem with ``node``, or raise it.
+
+        Problems are only recorded if a ``problems`` list was passed to
+        :func:`serialize`.  The caller then skips the offending node or
+        value, or substitutes a placeholder.  Otherwise, value errors
+        are raised as a :class:`SerializationError`.
+        """
+        if self.problems is None and isinstance(err, ValueError):
+            raise SerializationError(
+                str(err), category=category, attribute=attribute
+            ) from None
+        if self.problems is None:
+            raise err from None
+        self.found.append(
+            (self.offset(), Problem(_describe_path(node), category, str(err)))
+        )
 
-    if (
-        element.text is None
-        and len(element) == 0
-        and element.tag not in ALWAYS_EXPANDED_TAGS
-    ):
-        buffer.write(b"/>")
-        return pos + 2
-    buffer.write(b">")
-
-    child_indent = INDENT * (indent + 1)
-    if (element.text or "").strip():
-        pos = _serialize_text(
-            buffer,
-            element.text,
-            encoding=encoding,
-            errors=errors,
-            pos=pos,
-            multiline=True,
-        )
-        text_content = True
-    else:
-        text_content = False
-
-    for child in element:
-        if not text_content:
-            buffer.write(LINESEP)
-            buffer.write(child_indent)
-            pos = len(child_indent)
-
-        pos = _serialize_element(
-            buffer,
-            child,
-            indent + 1,
-            encoding=encoding,
-            errors=errors,
-            pos=pos,
-            line_length=line_length,
-        )
-        if (element.tail or "").strip():
-            pos = _serialize_text(
-                buffer, element.tail, encoding=encoding, errors=errors, pos=pos
+    def report_problems(self) -> None:
+        """Add the problems found so far to the ``problems`` list.
+
+        The line and column of each problem are resolved from its
+        offset here, so that the output is only scanned once.
+        """
+        if self.problems is None:
+            return
+        positions = _output_positions(
+            self.buffer.getvalue(),
+            [offset for offset, _ in self.found],
+            self.linesep,
+            self.encoding,
+        )
+        for (_, problem), (line, column) in zip(self.found, positions):
+            self.problems.append(problem._replace(line=line, column=column))
+        self.found.clear()
+
+    def significant(
+        self, text: str | None, *, verbatim: bool = False
+    ) -> str | None:
+        """Return ``text``, or None if it should be treated as absent.
+
+        Unless whitespace normalization is disabled, or the text is
+        part of a verbatim subtree, text that consists only of
+        whitespace is considered absent.  In reindent mode, leading and
+        trailing whitespace is collapsed to a single space as well.
+        """
+        if not isinstance(text, str):
+            return None
+        if verbatim:
+            return text
+        if self.reindent:
+            content = text.strip(XML_WHITESPACE)
+            if not content:
+                return None
+            leading = " " if text[0] in XML_WHITESPACE else ""
+            trailing = " " if text[-1] in XML_WHITESPACE else ""
+            return leading + content + trailing
+        if self.normalize_whitespace and not text.strip():
+            return None
+        return text
+
+    def is_expanded(self, element: lxml.etree._Element) -> bool:
+        if self.empty_elements == "default":
+            return element.tag in self.expanded_tags
+        return self.empty_elements == "expand"
+
+    def serialize_text(
+        self,
+        text: str,
+        /,
+        *,
+        pos: int,
+        multiline: bool = False,
+        comment: bool = False,
+    ) -> int:
+        text = self.normalize(text)
+        if comment and self.ascii_only and not text.isascii():
+            raise ValueError(
+                f"Cannot write non-ASCII comment in ASCII-only mode: {text!r}"
             )
-            text_content = True
+        for i, line in enumerate(self.splitlines(text)):
+            self.check_cancelled()
+            if multiline and i and self.inline:
+                raise _DoesNotFit
+            if multiline and i:
+                self.buffer.write(self.linesep)
+                pos = 0
+            if comment:
+                escaped = _escape_comment(
+                    line,
+                    invalid_chars=self.invalid_chars,
+                    noncharacters=self.noncharacters,
+                )
+            else:
+                escaped = _escape(
+                    line,
+                    pattern=self.text_pattern,
+                    ncr_style=self.ncr_style,
+                    invalid_chars=self.invalid_chars,
+                    xml_version=self.xml_version,
+                    ascii_only=self.ascii_only,
+                    charset=self.charset,
+                    noncharacters=self.noncharacters,
+                )
+            self.buffer.write(escaped.encode(self.encoding, self.errors))
+            pos += self.width(line, escaped)
+        return pos
+
+    def serialize_cdata(self, text: str, /, *, pos: int) -> int:
+        """Serialize ``text`` as one or more CDATA sections.
+
+        A ``]]>`` in the text would end the section early, so the text
+        is split between the ``]]`` and the ``>`` into two sections.
+        Parsers turn a carriage return inside a section into a line
+        break, so it is written as character reference between two
+        sections instead.  Nothing else inside a section is escaped,
+        therefore characters that the output encoding cannot represent
+        are an error.
+        """
+        text = self.normalize(text)
+        if self.ascii_only and not text.isascii():
+            raise _InvalidCharacter(
+                "Cannot write non-ASCII characters into a CDATA section"
+            )
+        if self.charset is not None:
+            text.encode(self.charset)
+
+        text = text.replace("]]>", "]]]]><![CDATA[>")
+        if self.ncr_style == "decimal":
+            carriage_return = "]]>&#13;<![CDATA["
         else:
-            text_content = False
+            carriage_return = "]]>&#xD;<![CDATA["
+        self.buffer.write(b"<![CDATA[")
+        pos += 9
+        for i, line in enumerate(self.splitlines(text)):
+            self.check_cancelled()
+            if i and self.inline:
+                raise _DoesNotFit
+            if i:
+                self.buffer.write(self.linesep)
+                pos = 0
+            line = _escape_comment(
+                line,
+                invalid_chars=self.invalid_chars,
+                noncharacters=self.noncharacters,
+            ).replace("\r", carriage_return)
+            self.buffer.write(line.encode(self.encoding, self.errors))
+            pos += len(line)
+        self.buffer.write(b"]]>")
+        return pos + 3
+
+    def normalize(self, text: str) -> str:
+        """Apply the requested Unicode normalization form to ``text``."""
+        form = self.normalize_unicode
+        if form is None or unicodedata.is_normalized(form, text):
+            return text
+        return unicodedata.normalize(form, text)
+
+    def splitlines(self, text: str) -> list[str]:
+        if self.normalize_text_newlines:
+            text = text.replace("\r\n", "\n").replace("\r", "\n")
+        return text.split("\n")
+
+    def width(self, raw: str, escaped: str) -> int:
+        if self.count_escapes or self.ascii_only:
+            return len(escaped)
+        return len(raw)
+
+
+def _describe(element: lxml.etree._Element) -> str:
+    description = f"element {element.tag!r}"
+    for attr in ("id", f"{{{NS_XMI}}}id"):
+        if (element_id := element.get(attr)) is not None:
+            description += f" (id={element_id})"
+            break
+    return description
+
+
+def _choose_prefixes(
+    namespaces: cabc.Mapping[str | None, str],
+    prefix_choice: str | dict[str, int],
+) -> dict[str, str]:
+    """Choose the prefix to use for each namespace URI.
+
+    See the ``prefix_choice`` parameter of :func:`serialize`.
+    """
+    candidates = [(k, v) for k, v in namespaces.items() if k is not None]
+    if prefix_choice == "last-declared":
+        candidates.reverse()
+    elif prefix_choice == "alphabetical":
+        candidates.sort()
+    elif isinstance(preference := prefix_choice, dict):
+        unprioritized = len(preference)
+        candidates.sort(key=lambda i: preference.get(i[0], unprioritized))
+
+    nsmap: dict[str, str] = {}
+    for prefix, uri in candidates:
+        nsmap.setdefault(uri, prefix)
+    return nsmap
+
+
+def _declared_namespaces(
+    root: lxml.etree._Element,
+) -> dict[str | None, str]:
+    """Collect the namespaces declared anywhere in a tree.
+
+    Raises
+    ------
+    ValueError
+        If the same prefix is bound to different namespaces.
+    """
+    namespaces: dict[str | None, str] = {}
+    unqualified: lxml.etree._Element | None = None
+    for element in root.iter():
+        if not isinstance(element.tag, str):
+            continue
+        if unqualified is None and not element.tag.startswith("{"):
+            unqualified = element
+        for prefix, uri in element.nsmap.items():
+            if namespaces.setdefault(prefix, uri) != uri:
+                raise ValueError(
+                    f"Cannot hoist namespaces: Prefix {prefix!r} is bound"
+                    f" to both {namespaces[prefix]!r} and {uri!r},"
+                    f" the latter at {_describe(element)}"
+                )
+
+    if None in namespaces and unqualified is not None:
+        raise ValueError(
+            "Cannot hoist the default namespace"
+            f" above the unqualified {_describe(unqualified)}"
+        )
+    return namespaces
+
 
-    if len(element) > 0 and not text_content:
-        buffer.write(LINESEP)
-        buffer.write(INDENT * indent)
-        pos = len(INDENT) * indent
+def _locate(
+    err: Exception,
+    node: lxml.etree._Element,
+    bytes_written: int,
+    position: str,
+) -> Exception:
+    """Add the output position and path of ``node`` to ``err``.
 
-    buffer.write(b"</")
-    buffer.write(tag)
-    buffer.write(b">")
-    return pos + len(tag) + 3
+    Value errors are turned into a :class:`SerializationError`.
+    """
+    path = _describe_path(node)
+    message = f"{err}\n  at {position}\n  at {path}"
+    located: Exception
+    if isinstance(err, SerializationError):
+        located = SerializationError(
+            message,
+            category=err.category,
+            element_path=path,
+            attribute=err.attribute,
+            bytes_written=bytes_written,
+        )
+    elif isinstance(err, ValueError):
+        if isinstance(err, (UnicodeError, _InvalidCharacter)):
+            category = "character"
+        else:
+            category = "tree"
+        located = SerializationError(
+            message,
+            category=category,
+            element_path=path,
+            bytes_written=bytes_written,
+        )
+    else:
+        located = type(err)(message)
+    return located.with_traceback(err.__traceback__)
 
 
-def _serialize_text(
-    buffer: _HasWrite,
-    text: str,
-    /,
-    *,
+def _output_positions(
+    data: bytes,
+    offsets: cabc.Iterable[int],
+    linesep: bytes,
     encoding: str,
-    errors: str,
-    pos: int,
-    multiline: bool = False,
-    pattern: re.Pattern[str] = P_ESCAPE_TEXT,
-) -> int:
-    i, line = 0, ""
-    for i, line in enumerate(text.split("\n")):
-        if multiline and i:
-            buffer.write(LINESEP)
-        buffer.write(_escape(line, pattern=pattern).encode(encoding, errors))
-    return len(line) + bool(i) * pos
+) -> cabc.Iterator[tuple[int, int]]:
+    """Find the line and column of each offset in the output ``data``.
+
+    The offsets must be in ascending order, so that the output is only
+    scanned once.  Lines and columns are counted from 1, and columns
+    are counted in characters.
+    """
+    line = 1
+    start = scanned = 0
+    for offset in offsets:
+        line += data.count(linesep, scanned, offset)
+        newline = data.rfind(linesep, scanned, offset)
+        if newline >= 0:
+            start = newline + len(linesep)
+        scanned = offset
+        column = len(data[start:offset].decode(encoding, "replace")) + 1
+        yield line, column
+
+
+def _describe_position(data: bytes, linesep: bytes, encoding: str) -> str:
+    """Describe the position after ``data`` in the output."""
+    [(line, column)] = _output_positions(data, [len(data)], linesep, encoding)
+    return f"output line {line}, column {column}"
+
+
+def _describe_path(node: lxml.etree._Element) -> str:
+    """Describe the position of ``node`` in its tree.
+
+    The path consists of the local names of the node and its ancestors.
+    Where siblings share a name, the position among them is appended,
+    like in XPath.  The ID of ``node`` is appended, if it has one.
+    """
+    segments = []
+    for i in itertools.chain((node,), node.iterancestors()):
+        if isinstance(i.tag, str):
+            segment = i.tag.rpartition("}")[2]
+        elif isinstance(i, lxml.etree._Comment):
+            segment = "comment()"
+        elif isinstance(i, lxml.etree._ProcessingInstruction):
+            segment = "processing-instruction()"
+        else:
+            segment = "node()"
+        if (parent := i.getparent()) is not None:
+            siblings = [j for j in parent if j.tag == i.tag]
+            if len(siblings) > 1:
+                segment += f"[{siblings.index(i) + 1}]"
+        segments.append(segment)
+    path = "/" + "/".join(reversed(segments))
 
+    if isinstance(node.tag, str):
+        for attr in ("id", f"{{{NS_XMI}}}id"):
+            if (node_id := node.get(attr)) is not None:
+                return f"{path} (id={node_id})"
+    return path
 
-def _unmap_namespace(nsmap: cabc.Mapping[str, str], name: str) -> str:
+
+def _location(element: lxml.etree._Element | None) -> str:
+    if element is None:
+        return ""
+    return f" at {_describe_nearest(element)}"
+
+
+def _describe_node(node: lxml.etree._Element) -> str:
+    """Describe a node that is not an element, like a PI or entity.
+
+    Besides a shortened ``repr()`` of the node, this includes its index
+    among the children of its parent, and the parent itself.
+    """
+    kind = type(node).__name__.lstrip("_")
+    description = repr(node)
+    if len(description) > MAX_REPR_LENGTH:
+        description = description[: MAX_REPR_LENGTH - 3] + "..."
+    parent = node.getparent()
+    if parent is None:
+        return f"{kind} {description}"
+    return (
+        f"{kind} {description} at index {parent.index(node)}"
+        f" of {_describe_nearest(parent)}"
+    )
+
+
+def _describe_nearest(element: lxml.etree._Element) -> str:
+    """Describe ``element`` by way of its nearest identifiable ancestor."""
+    for i in itertools.chain((element,), element.iterancestors()):
+        if i.get("id") is None and i.get(f"{{{NS_XMI}}}id") is None:
+            continue
+        if i is element:
+            break
+        return f"{_describe(element)} below {_describe(i)}"
+    return _describe(element)
+
+
+def _find_shadowing(
+    element: lxml.etree._Element, uri: str
+) -> tuple[str, str] | None:
+    """Find the binding that makes an inherited namespace unreachable.
+
+    Returns
+    -------
+    tuple[str, str] | None
+        The prefix that an ancestor binds ``uri`` to, along with the
+        namespace that it is rebound to at ``element``, or None if no
+        ancestor binds ``uri`` to a prefix.
+    """
+    nsmap = element.nsmap
+    for ancestor in element.iterancestors():
+        for prefix, ancestor_uri in ancestor.nsmap.items():
+            if prefix is not None and ancestor_uri == uri:
+                return prefix, nsmap.get(prefix, "")
+    return None
+
+
+def _unmap_namespace(
+    nsmap: cabc.Mapping[str, str],
+    name: str | lxml.etree.QName,
+    *,
+    default: str | None = None,
+    element: lxml.etree._Element | None = None,
+) -> str:
+    """Convert a name in Clark notation into its prefixed form.
+
+    Parameters
+    ----------
+    nsmap
+        A mapping from namespace URIs to their prefixes.  The ``xml``
+        prefix is implicitly bound and need not be contained in it.
+    name
+        The name to convert, either as string or as QName.
+    default
+        The default namespace, if any.  Names in this namespace are
+        written without a prefix.  Attributes never use the default
+        namespace, so this must only be passed for tags.
+    element
+        The element that the name belongs to, which is used to point
+        out its location in error messages.
+    """
+    if isinstance(name, lxml.etree.QName):
+        name = name.text
+    if not isinstance(name, str):
+        raise TypeError(
+            f"Names must be strings, not {type(name).__name__}"
+            + _location(element)
+        )
     match = P_NAME.search(name)
-    assert match is not None
-    if ns := match.group(1) or "":
+    if match is None or "{" in match.group(2) or "}" in match.group(2):
+        raise ValueError(f"Malformed name {name!r}{_location(element)}")
+    ns = match.group(1) or ""
+    if ns == NS_XML:
+        ns = "xml"
+    elif ns and ns == default:
+        ns = ""
+    elif ns:
         try:
-            ns = nsmap[ns]
+            prefix = nsmap[ns]
         except KeyError:
-            raise ValueError(f"Namespace not found: {ns!r}") from None
-        assert ns
+            location = ""
+            if element is not None:
+                location = f" at {_describe_nearest(element)}"
+                if shadowed := _find_shadowing(element, ns):
+                    raise ValueError(
+                        f"Namespace {ns!r} of {match.group(2)!r}"
+                        f" is unreachable{location}, because its"
+                        f" prefix {shadowed[0]!r} is rebound"
+                        f" to {shadowed[1]!r}"
+                    ) from None
+            raise ValueError(
+                f"Namespace {ns!r} of {match.group(2)!r}"
+                f" is not declared in the nsmap{location}"
+            ) from None
+        if not prefix:
+            raise ValueError(
+                f"Namespace {ns!r} of {match.group(2)!r} is bound"
+                f" to an empty prefix{_location(element)}"
+            )
+        ns = prefix
+    elif default:
+        raise ValueError(
+            f"Cannot write {name!r} without namespace"
+            f" inside the default namespace {default!r}"
+        )
 
     tag = match.group(2)
-    assert tag
 
     return "".join((ns, ns and ":", tag))
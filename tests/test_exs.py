@@ -0,0 +1,3519 @@
+# Copyright DB Netz AG and the capellambse contributors
+# SPDX-License-Identifier: Apache-2.0
+
+import importlib.util
+import io
+import itertools
+import math
+import re
+import tracemalloc
+import types
+import unicodedata
+import warnings
+
+import lxml
+import pytest
+from lxml import etree
+
+from capellambse.loader import exs
+
+from . import TEST_ROOT
+
+CAPELLA_FILE = TEST_ROOT / "5_0" / "Melody Model Test.capella"
+DEFAULT_NS_FILE = TEST_ROOT.parent / "exs" / "Default Namespace.afm"
+NS_CAPELLACORE_1_4 = "http://www.polarsys.org/capella/core/core/1.4.0"
+NS_CAPELLACORE_5_0 = "http://www.polarsys.org/capella/core/core/5.0.0"
+
+UNUSED_NAMESPACES_XML = """\
+<root xmlns:unused="urn:unused" xmlns:used="urn:used" xmlns:gone="urn:gone">
+  <child used:name="Child"/>
+</root>
+"""
+
+MOJIBAKE_XML = b'<root name="Caf\xe9 \xff\xfe"/>'
+
+NESTED_XML = """\
+<root id="root-id" name="Root">
+  <child id="child-id" name="Child">
+    <leaf id="leaf-id" name="Leaf"/>
+  </child>
+</root>
+"""
+
+
+def test_capella_files_are_reproduced_byte_for_byte():
+    expected = CAPELLA_FILE.read_bytes()
+    buffer = io.BytesIO()
+
+    exs.write(etree.parse(str(CAPELLA_FILE)), buffer)
+
+    assert buffer.getvalue() == expected
+
+
+def test_indent_width_changes_the_indentation_of_nested_elements():
+    tree = etree.fromstring(NESTED_XML)
+    expected = (
+        b'<root id="root-id"\n'
+        b'        name="Root">\n'
+        b'    <child id="child-id" name="Child">\n'
+        b'        <leaf id="leaf-id" name="Leaf"/>\n'
+        b"    </child>\n"
+        b"</root>\n"
+    )
+
+    actual = exs.serialize(tree, indent_width=4).replace(exs.LINESEP, b"\n")
+
+    assert actual == expected
+
+
+def test_indent_width_applies_to_wrapped_attributes():
+    tree = etree.fromstring(
+        '<root><child a="aaaaaaaaaa" b="bbbbbbbbbb" c="cccccccccc"/></root>'
+    )
+    expected = (
+        b"<root>\n"
+        b'   <child a="aaaaaaaaaa" b="bbbbbbbbbb"\n'
+        b'         c="cccccccccc"/>\n'
+        b"</root>\n"
+    )
+
+    actual = exs.serialize(tree, indent_width=3, line_length=30)
+
+    assert actual.replace(exs.LINESEP, b"\n") == expected
+
+
+def test_indent_width_applies_to_multi_line_comments():
+    tree = etree.fromstring(
+        "<root><child><!--first\n  second\nthird--><leaf/></child></root>"
+    )
+    expected = (
+        b"<root>\n"
+        b"    <child>\n"
+        b"        <!--first\n"
+        b"          second\n"
+        b"        third-->\n"
+        b"        <leaf/>\n"
+        b"    </child>\n"
+        b"</root>\n"
+    )
+
+    actual = exs.serialize(tree, indent_width=4, newline="\n")
+
+    assert actual == expected
+
+
+@pytest.mark.parametrize("indent_width", [-1, exs.MAX_INDENT_WIDTH + 1])
+def test_indent_width_outside_of_sensible_range_is_rejected(indent_width):
+    tree = etree.fromstring(NESTED_XML)
+
+    with pytest.raises(ValueError, match="indent_width"):
+        exs.serialize(tree, indent_width=indent_width)
+
+
+def test_indent_string_may_contain_tabs():
+    tree = etree.fromstring(NESTED_XML)
+    expected = (
+        b'<root id="root-id"\n'
+        b'\t\tname="Root">\n'
+        b'\t<child id="child-id" name="Child">\n'
+        b'\t\t<leaf id="leaf-id" name="Leaf"/>\n'
+        b"\t</child>\n"
+        b"</root>\n"
+    )
+
+    actual = exs.serialize(tree, indent="\t").replace(exs.LINESEP, b"\n")
+
+    assert actual == expected
+
+
+def test_tab_indentation_counts_rendered_width_for_wrapping():
+    tree = etree.fromstring(
+        '<root><child a="aaaaaaaaaa" b="bbbbbbbbbb" c="cccccccccc"/></root>'
+    )
+    expected = (
+        b"<root>\n"
+        b'\t<child a="aaaaaaaaaa"\n'
+        b'\t\t\tb="bbbbbbbbbb"\n'
+        b'\t\t\tc="cccccccccc"/>\n'
+        b"</root>\n"
+    )
+
+    actual = exs.serialize(tree, indent="\t", line_length=25)
+
+    assert actual.replace(exs.LINESEP, b"\n") == expected
+
+
+@pytest.mark.parametrize("indent", ["x", " \n", "\u00a0"])
+def test_indent_string_with_non_whitespace_is_rejected(indent):
+    tree = etree.fromstring(NESTED_XML)
+
+    with pytest.raises(ValueError, match="spaces and tabs"):
+        exs.serialize(tree, indent=indent)
+
+
+@pytest.mark.parametrize("newline", ["\n", "\r\n"])
+def test_newline_is_used_for_all_line_breaks(newline):
+    tree = etree.fromstring(NESTED_XML)
+    expected = (
+        '<root id="root-id"\n'
+        '    name="Root">\n'
+        '  <child id="child-id" name="Child">\n'
+        '    <leaf id="leaf-id" name="Leaf"/>\n'
+        "  </child>\n"
+        "</root>\n"
+    ).replace("\n", newline)
+
+    actual = exs.serialize(tree, newline=newline)
+
+    assert actual == expected.encode("ascii")
+
+
+def test_newline_is_used_for_the_xml_declaration():
+    tree = etree.fromstring(NESTED_XML)
+    buffer = io.BytesIO()
+
+    exs.write(tree, buffer, newline="\r\n")
+
+    assert buffer.getvalue().startswith(
+        b'<?xml version="1.0" encoding="UTF-8"?>\r\n<root'
+    )
+    assert buffer.getvalue().count(b"\n") == buffer.getvalue().count(b"\r\n")
+
+
+def test_trailing_newline_defaults_to_a_line_feed(monkeypatch):
+    tree = etree.fromstring(NESTED_XML)
+    monkeypatch.setattr(exs, "LINESEP", b"\r\n")
+
+    actual = exs.serialize(tree)
+
+    assert actual.endswith(b"\r\n</root>\n")
+
+
+def test_unsupported_newline_is_rejected():
+    tree = etree.fromstring(NESTED_XML)
+
+    with pytest.raises(ValueError, match="newline"):
+        exs.serialize(tree, newline="\r")
+
+
+@pytest.mark.parametrize("line_length", [0, None])
+def test_line_length_zero_or_none_disables_attribute_wrapping(line_length):
+    attributes = "".join(f' attr{i}="value{i}"' for i in range(50))
+    tree = etree.fromstring(f'<root id="root-id"><child{attributes}/></root>')
+    expected = f'<root id="root-id">\n  <child{attributes}/>\n</root>\n'
+
+    actual = exs.serialize(tree, line_length=line_length, newline="\n")
+
+    assert actual == expected.encode("ascii")
+
+
+def test_line_length_counts_characters_not_encoded_bytes():
+    tree = etree.fromstring(
+        '<root><child a="äöüäöüäöü" b="ÄÖÜÄÖÜÄÖÜ" c="ßßßßßßßßß"/></root>'
+    )
+    expected = (
+        "<root>\n"
+        '  <child a="äöüäöüäöü" b="ÄÖÜÄÖÜÄÖÜ"\n'
+        '      c="ßßßßßßßßß"/>\n'
+        "</root>\n"
+    ).encode("utf-8")
+
+    actual = exs.serialize(tree, line_length=30, newline="\n")
+
+    assert actual == expected
+
+
+@pytest.mark.parametrize(
+    "count_escapes,expected",
+    [
+        pytest.param(
+            True,
+            '  <child a="&lt;&lt;&lt;"\n      b="&amp;&amp;&amp;"/>',
+            id="escaped-length",
+        ),
+        pytest.param(
+            False,
+            '  <child a="&lt;&lt;&lt;" b="&amp;&amp;&amp;"/>',
+            id="source-length",
+        ),
+    ],
+)
+def test_count_escapes_controls_the_width_of_escaped_characters(
+    count_escapes, expected
+):
+    tree = etree.fromstring(
+        '<root><child a="&lt;&lt;&lt;" b="&amp;&amp;&amp;"/></root>'
+    )
+
+    actual = exs.serialize(
+        tree, line_length=22, count_escapes=count_escapes, newline="\n"
+    )
+
+    assert actual == f"<root>\n{expected}\n</root>\n".encode("ascii")
+
+
+def test_attr_per_line_puts_every_attribute_on_its_own_line():
+    tree = etree.fromstring(
+        '<xmi:XMI xmlns:xmi="http://www.omg.org/XMI" xmi:version="2.0"'
+        ' id="root-id"><child id="child-id" name="Child"/><empty/></xmi:XMI>'
+    )
+    expected = (
+        b"<xmi:XMI\n"
+        b'    xmi:version="2.0"\n'
+        b'    xmlns:xmi="http://www.omg.org/XMI"\n'
+        b'    id="root-id">\n'
+        b"  <child\n"
+        b'      id="child-id"\n'
+        b'      name="Child"/>\n'
+        b"  <empty/>\n"
+        b"</xmi:XMI>\n"
+    )
+
+    actual = exs.serialize(tree, attr_per_line=True, newline="\n")
+
+    assert actual == expected
+    reparsed = etree.fromstring(actual)
+    assert exs.serialize(reparsed, attr_per_line=True, newline="\n") == actual
+
+
+def test_attr_align_first_aligns_wrapped_attributes_with_the_first():
+    tree = etree.fromstring(
+        '<root id="root-id" name="Root">'
+        '<child a="aaaaaaaaaa" b="bbbbbbbbbb" c="cccccccccc"/></root>'
+    )
+    expected = (
+        b'<root id="root-id"\n'
+        b'      name="Root">\n'
+        b'  <child a="aaaaaaaaaa" b="bbbbbbbbbb"\n'
+        b'         c="cccccccccc"/>\n'
+        b"</root>\n"
+    )
+
+    actual = exs.serialize(
+        tree, attr_align="first", line_length=30, newline="\n"
+    )
+
+    assert actual == expected
+
+
+def test_attr_align_first_aligns_with_the_first_after_inline_text():
+    tree = etree.fromstring(
+        '<root><p>Some text <b x="1111111111" y="2222222222"/></p></root>'
+    )
+    expected = (
+        b'  <p>Some text <b x="1111111111"\n'
+        b'                 y="2222222222"/>\n'
+    )
+
+    actual = exs.serialize(
+        tree, attr_align="first", line_length=30, newline="\n"
+    )
+
+    assert expected in actual
+
+
+def test_attr_align_first_falls_back_to_indent_for_overlong_tags():
+    tree = etree.fromstring(
+        "<root><a><b><c>"
+        '<aVeryLongTagNameIndeed first="1" second="2"/>'
+        "</c></b></a></root>"
+    )
+    expected = (
+        b"        <aVeryLongTagNameIndeed\n"
+        b'            first="1"\n'
+        b'            second="2"/>\n'
+    )
+
+    actual = exs.serialize(
+        tree, attr_align="first", line_length=20, newline="\n"
+    )
+
+    assert expected in actual
+
+
+@pytest.mark.parametrize("continuation_indent", [1, 2, 3])
+def test_continuation_indent_controls_wrapped_attribute_indentation(
+    continuation_indent,
+):
+    tree = etree.fromstring(
+        '<root><child a="aaaaaaaaaa" b="bbbbbbbbbb" c="cccccccccc"/></root>'
+    )
+    prefix = "  " * (1 + continuation_indent)
+    expected = (
+        "<root>\n"
+        '  <child a="aaaaaaaaaa" b="bbbbbbbbbb"\n'
+        f'{prefix}c="cccccccccc"/>\n'
+        "</root>\n"
+    ).encode("ascii")
+
+    actual = exs.serialize(
+        tree,
+        continuation_indent=continuation_indent,
+        line_length=30,
+        newline="\n",
+    )
+    reserialized = exs.serialize(
+        etree.fromstring(actual),
+        continuation_indent=continuation_indent,
+        line_length=30,
+        newline="\n",
+    )
+
+    assert actual == expected
+    assert reserialized == expected
+
+
+def test_continuation_indent_of_zero_is_rejected():
+    tree = etree.fromstring(NESTED_XML)
+
+    with pytest.raises(ValueError, match="continuation_indent"):
+        exs.serialize(tree, continuation_indent=0)
+
+
+@pytest.mark.parametrize(
+    "break_after_id,expected",
+    [
+        (True, b'<root id="root-id"\n    name="Root"/>\n'),
+        (False, b'<root id="root-id" name="Root"/>\n'),
+    ],
+)
+def test_break_after_id_controls_the_forced_wrap_on_the_root(
+    break_after_id, expected
+):
+    tree = etree.fromstring('<root id="root-id" name="Root"/>')
+
+    actual = exs.serialize(tree, break_after_id=break_after_id, newline="\n")
+
+    assert actual == expected
+
+
+@pytest.mark.parametrize(
+    "empty_elements,expected",
+    [
+        ("default", b"<root>\n  <bodies></bodies>\n  <owned/>\n</root>\n"),
+        ("self-close", b"<root>\n  <bodies/>\n  <owned/>\n</root>\n"),
+        (
+            "expand",
+            b"<root>\n  <bodies></bodies>\n  <owned></owned>\n</root>\n",
+        ),
+    ],
+)
+def test_empty_elements_controls_self_closing_tags(empty_elements, expected):
+    tree = etree.fromstring("<root><bodies/><owned/></root>")
+
+    actual = exs.serialize(tree, empty_elements=empty_elements, newline="\n")
+    reserialized = exs.serialize(
+        etree.fromstring(actual), empty_elements=empty_elements, newline="\n"
+    )
+
+    assert actual == expected
+    assert reserialized == expected
+
+
+def test_expanded_tags_replaces_the_builtin_set():
+    tree = etree.fromstring("<root><bodies/><description/></root>")
+    expected = b"<root>\n  <bodies/>\n  <description></description>\n</root>\n"
+
+    actual = exs.serialize(tree, expanded_tags=["description"], newline="\n")
+
+    assert actual == expected
+
+
+def test_expanded_tags_can_extend_the_builtin_set():
+    tree = etree.fromstring("<root><bodies/><description/></root>")
+    expected = (
+        b"<root>\n"
+        b"  <bodies></bodies>\n"
+        b"  <description></description>\n"
+        b"</root>\n"
+    )
+
+    actual = exs.serialize(
+        tree,
+        expanded_tags=exs.ALWAYS_EXPANDED_TAGS | {"description"},
+        newline="\n",
+    )
+
+    assert actual == expected
+
+
+def test_expanded_tags_with_non_string_entries_are_rejected():
+    tree = etree.fromstring("<root><bodies/></root>")
+
+    with pytest.raises(TypeError, match="expanded_tags"):
+        exs.serialize(tree, expanded_tags=["bodies", 1])
+
+
+@pytest.mark.parametrize("prefix", ["ns", "other"])
+def test_expanded_tags_match_namespaced_tags_independent_of_prefix(prefix):
+    tree = etree.fromstring(
+        f'<{prefix}:root xmlns:{prefix}="http://example.com/ns">'
+        f"<{prefix}:bodies/><{prefix}:owned/></{prefix}:root>"
+    )
+    expected = (
+        f'<{prefix}:root xmlns:{prefix}="http://example.com/ns">\n'
+        f"  <{prefix}:bodies></{prefix}:bodies>\n"
+        f"  <{prefix}:owned/>\n"
+        f"</{prefix}:root>\n"
+    ).encode("ascii")
+
+    actual = exs.serialize(
+        tree, expanded_tags=["{http://example.com/ns}bodies"], newline="\n"
+    )
+
+    assert actual == expected
+
+
+def test_trailing_newline_can_be_suppressed():
+    tree = etree.fromstring(NESTED_XML)
+    buffer = io.BytesIO()
+
+    actual = exs.serialize(tree, trailing_newline=False, newline="\n")
+    exs.write(tree, buffer, trailing_newline=False, newline="\n")
+
+    assert actual.endswith(b"</root>")
+    assert buffer.getvalue().endswith(b"</root>")
+
+
+def test_trailing_newline_suppression_keeps_sibling_comments_apart():
+    tree = etree.fromstring("<root/><!-- first --><!-- second -->")
+    expected = b"<root/>\n<!-- first -->\n\n<!-- second -->"
+
+    actual = exs.serialize(
+        tree.getroottree(), trailing_newline=False, newline="\n"
+    )
+
+    assert actual == expected
+
+
+def test_exceeding_max_depth_raises_a_recursion_error():
+    root = parent = etree.Element("root")
+    for i in range(100_000):
+        parent = etree.SubElement(parent, "child", id=f"id-{i}")
+
+    with pytest.raises(RecursionError, match=r"'child' \(id=id-99\)"):
+        exs.serialize(root, max_depth=100)
+
+
+def test_max_depth_default_triggers_before_the_interpreter_limit():
+    root = parent = etree.Element("root")
+    for _ in range(100_000):
+        parent = etree.SubElement(parent, "child")
+
+    with pytest.raises(RecursionError, match="Maximum nesting depth"):
+        exs.serialize(root)
+
+
+def test_compact_keeps_small_elements_with_children_on_one_line():
+    tree = etree.fromstring(
+        '<root id="root-id" name="Root">'
+        '<wrapper><child id="a"/></wrapper>'
+        '<wrapper><child id="b" name="A child with a very long name"/>'
+        "<child/></wrapper>"
+        "</root>"
+    )
+    expected = (
+        b'<root id="root-id"\n'
+        b'    name="Root">\n'
+        b'  <wrapper><child id="a"/></wrapper>\n'
+        b"  <wrapper>\n"
+        b'    <child id="b" name="A child with a very long name"/>\n'
+        b"    <child/>\n"
+        b"  </wrapper>\n"
+        b"</root>\n"
+    )
+
+    actual = exs.serialize(tree, compact=True, line_length=50, newline="\n")
+
+    assert actual == expected
+
+
+def test_compact_expands_elements_containing_multiline_text():
+    tree = etree.fromstring("<root><wrapper><a>x\ny</a></wrapper></root>")
+    expected = b"<root>\n  <wrapper>\n    <a>x\ny</a>\n  </wrapper>\n</root>\n"
+
+    actual = exs.serialize(tree, compact=True, newline="\n")
+
+    assert actual == expected
+
+
+def test_expanded_empty_elements_match_capella_output():
+    capella_snippet = (
+        TEST_ROOT / "1_3" / "MelodyModelTest.melodymodeller"
+    ).read_bytes()
+    tree = etree.fromstring("<root><bodies/></root>")
+
+    actual = exs.serialize(tree)
+
+    assert b"<bodies></bodies>" + exs.LINESEP in capella_snippet
+    assert b"<bodies></bodies>" + exs.LINESEP in actual
+
+
+def test_empty_element_linebreak_puts_the_closing_tag_on_its_own_line():
+    tree = etree.fromstring("<root><bodies/><bodies>text</bodies></root>")
+    expected = (
+        b"<root>\n"
+        b"  <bodies>\n"
+        b"  </bodies>\n"
+        b"  <bodies>text</bodies>\n"
+        b"</root>\n"
+    )
+
+    actual = exs.serialize(tree, empty_element_linebreak=True, newline="\n")
+
+    assert actual == expected
+
+
+def test_wrap_attr_values_splits_long_values_at_spaces():
+    points = " ".join(f"{i},{i * 2}" for i in range(20))
+    tree = etree.Element("root")
+    etree.SubElement(tree, "edge", points=points, name='"quoted" & <more>')
+
+    actual = exs.serialize(
+        tree, wrap_attr_values=True, line_length=40, newline="\n"
+    )
+
+    lines = actual.split(b"\n")
+    assert len(lines) > 5
+    assert all(len(i) <= 40 for i in lines[2:-3])
+    edge = etree.fromstring(actual)[0]
+    assert edge.get("points") == points
+    assert edge.get("name") == '"quoted" & <more>'
+
+
+def test_wrap_attr_values_keeps_short_values_unchanged():
+    tree = etree.fromstring(NESTED_XML)
+
+    actual = exs.serialize(tree, wrap_attr_values=True)
+
+    assert actual == exs.serialize(tree)
+
+
+@pytest.mark.parametrize(
+    "quote_char,expected",
+    [
+        ('"', b'<root value="it\'s &quot;quoted&quot; &amp; &lt;&#x9;>"/>\n'),
+        ("'", b"<root value='it&apos;s \"quoted\" &amp; &lt;&#x9;>'/>\n"),
+    ],
+)
+def test_quote_char_selects_the_attribute_delimiter(quote_char, expected):
+    tree = etree.Element("root", value='it\'s "quoted" & <\t>')
+
+    actual = exs.serialize(tree, quote_char=quote_char, newline="\n")
+
+    assert actual == expected
+
+
+def test_unsupported_quote_char_is_rejected():
+    tree = etree.fromstring(NESTED_XML)
+
+    with pytest.raises(ValueError, match="quote_char"):
+        exs.serialize(tree, quote_char="`")
+
+
+def test_sort_attributes_makes_output_independent_of_insertion_order():
+    nsmap = {"xmi": "http://www.omg.org/XMI"}
+    attributes = [
+        ("name", "Name"),
+        ("{http://www.omg.org/XMI}version", "2.0"),
+        ("{http://www.omg.org/XMI}id", "xmi-id"),
+        ("abstract", "false"),
+        ("id", "id"),
+    ]
+    first = etree.Element("root", nsmap=nsmap)
+    second = etree.Element("root", nsmap=nsmap)
+    for key, value in attributes:
+        first.set(key, value)
+    for key, value in reversed(attributes):
+        second.set(key, value)
+    expected = (
+        b'<root xmi:version="2.0" xmlns:xmi="http://www.omg.org/XMI"'
+        b' abstract="false" id="id"\n'
+        b'    name="Name" xmi:id="xmi-id"/>\n'
+    )
+
+    actual_first = exs.serialize(first, sort_attributes=True, newline="\n")
+    actual_second = exs.serialize(second, sort_attributes=True, newline="\n")
+
+    assert actual_first == expected
+    assert actual_second == expected
+
+
+def test_attr_order_writes_prioritized_attributes_first():
+    tree = etree.fromstring(
+        '<root xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance">'
+        '<child abstractType="t" id="a" value="v" name="n"/>'
+        '<child id="b" xsi:type="T" value="v"/>'
+        "</root>"
+    )
+    expected = (
+        b'<root xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance">\n'
+        b'  <child name="n" value="v" abstractType="t" id="a"/>\n'
+        b'  <child xsi:type="T" value="v" id="b"/>\n'
+        b"</root>\n"
+    )
+
+    actual = exs.serialize(
+        tree,
+        attr_order=["xsi:type", "name", "value", "abstractType"],
+        newline="\n",
+    )
+
+    assert actual == expected
+
+
+def test_reorder_special_attrs_false_keeps_the_stored_order():
+    tree = etree.fromstring(
+        '<xmi:XMI xmlns:xmi="http://www.omg.org/XMI"'
+        ' id="r" xmi:version="2.0" name="R"/>'
+    )
+    expected = (
+        b'<xmi:XMI xmlns:xmi="http://www.omg.org/XMI"'
+        b' id="r" xmi:version="2.0" name="R"/>\n'
+    )
+
+    actual = exs.serialize(tree, reorder_special_attrs=False, newline="\n")
+
+    assert actual == expected
+
+
+def _capella_root() -> etree._Element:
+    root = etree.parse(str(CAPELLA_FILE)).getroot()
+    tree = etree.Element(root.tag, nsmap=root.nsmap)
+    for key, value in root.items():
+        tree.set(key, value)
+    etree.SubElement(tree, "ownedModelRoots")
+    return tree
+
+
+def test_xmlns_position_after_specials_matches_capella_root_elements():
+    capella_file = CAPELLA_FILE.read_bytes()
+    start = capella_file.index(b"<org.polarsys.capella.core.data.")
+    end = capella_file.index(b">", start) + 1
+    expected = capella_file[start:end]
+
+    actual = exs.serialize(_capella_root(), xmlns_position="after-specials")
+
+    assert actual.startswith(expected)
+
+
+@pytest.mark.parametrize(
+    "xmlns_position,expected",
+    [
+        ("first", ["xmlns", "xmi:version", "id", "name"]),
+        ("last", ["xmi:version", "id", "name", "xmlns"]),
+    ],
+)
+def test_xmlns_position_controls_where_namespaces_are_declared(
+    xmlns_position, expected
+):
+    actual = exs.serialize(_capella_root(), xmlns_position=xmlns_position)
+
+    opening_tag = actual[: actual.index(b">")].decode("utf-8")
+    names = re.findall(r"([\w.:]+)=\"", opening_tag)
+    kinds = [i.split(":")[0] if i.startswith("xmlns:") else i for i in names]
+    assert [k for k, _ in itertools.groupby(kinds)] == expected
+
+
+def test_declaration_is_written_before_preceding_comments():
+    tree = etree.fromstring("<!--Capella_Version_5.0.0--><root/>")
+    expected = (
+        b'<?xml version="1.0" encoding="UTF-8"?>\n'
+        b"\n"
+        b"<!--Capella_Version_5.0.0-->\n"
+        b"<root/>\n"
+    )
+
+    actual = exs.serialize(tree.getroottree(), declaration=True, newline="\n")
+
+    assert actual == expected
+
+
+def test_declaration_contents_can_be_customized():
+    tree = etree.fromstring("<root/>")
+    expected = (
+        b'<?xml version="1.1" encoding="UTF-8" standalone="yes"?>\r\n'
+        b"<root/>\r\n"
+    )
+
+    actual = exs.serialize(
+        tree,
+        declaration={"version": "1.1", "standalone": True},
+        newline="\r\n",
+    )
+
+    assert actual == expected
+
+
+def test_declaration_of_a_different_encoding_is_rejected():
+    tree = etree.fromstring("<root/>")
+
+    with pytest.raises(ValueError, match="latin-1"):
+        exs.serialize(tree, declaration={"encoding": "latin-1"})
+
+
+def test_doctype_and_standalone_from_docinfo_are_reproduced():
+    document = (
+        b'<?xml version="1.0" encoding="UTF-8" standalone="no"?>\n'
+        b'<!DOCTYPE root SYSTEM "root.dtd">\n'
+        b"\n"
+        b"<!--leading comment-->\n"
+        b'<root id="root-id"/>\n'
+    )
+    tree = etree.fromstring(document).getroottree()
+
+    actual = exs.serialize(tree, declaration=True, newline="\n")
+
+    assert actual == document
+
+
+def test_explicit_doctype_is_written_after_the_declaration():
+    tree = etree.fromstring("<root/>")
+    expected = (
+        b'<?xml version="1.0" encoding="UTF-8"?>\n'
+        b"<!DOCTYPE root>\n"
+        b"<root/>\n"
+    )
+
+    actual = exs.serialize(
+        tree, declaration=True, doctype="<!DOCTYPE root>", newline="\n"
+    )
+
+    assert actual == expected
+
+
+def test_unterminated_doctype_is_rejected():
+    tree = etree.fromstring("<root/>")
+
+    with pytest.raises(ValueError, match="DOCTYPE"):
+        exs.serialize(tree, doctype="<!DOCTYPE root")
+
+
+def test_comments_false_drops_comments_but_keeps_their_tails():
+    tree = etree.fromstring(
+        "<!-- leading --><root>"
+        "<a>text<!-- inside -->tail</a>"
+        "<!-- between --><b/>"
+        "</root><!-- trailing -->"
+    )
+    expected = b"<root>\n  <a>texttail</a>\n  <b/>\n</root>\n"
+
+    actual = exs.serialize(tree.getroottree(), comments=False, newline="\n")
+
+    assert actual == expected
+
+
+def test_tails_are_written_after_the_child_they_belong_to():
+    tree = etree.fromstring("<root><a>text<b/>after b</a>after a<c/></root>")
+    expected = b"<root>\n  <a>text<b/>after b</a>after a<c/>\n</root>\n"
+
+    actual = exs.serialize(tree, newline="\n")
+
+    assert actual == expected
+
+
+def test_nested_comments_are_serialized_between_siblings():
+    tree = etree.fromstring("<root><a/><!-- between --><b/></root>")
+    expected = b"<root>\n  <a/>\n  <!-- between -->\n  <b/>\n</root>\n"
+
+    actual = exs.serialize(tree, newline="\n")
+
+    assert actual == expected
+
+
+def test_drop_attributes_leaves_out_plain_and_namespaced_attributes():
+    tree = etree.fromstring(
+        '<root xmlns:xmi="http://www.omg.org/XMI" xmi:version="2.0"'
+        ' xmi:id="abc" id="1" name="Root"><child uid="2" name="C"/></root>'
+    )
+    expected = (
+        b'<root xmi:version="2.0" xmlns:xmi="http://www.omg.org/XMI"'
+        b' name="Root">\n'
+        b'  <child name="C"/>\n'
+        b"</root>\n"
+    )
+    dropped = ["{http://www.omg.org/XMI}id", "id", "uid"]
+
+    actual = exs.serialize(tree, drop_attributes=dropped, newline="\n")
+
+    assert actual == expected
+    assert tree.get("{http://www.omg.org/XMI}id") == "abc"
+    assert tree.get("id") == "1"
+    assert tree[0].get("uid") == "2"
+
+
+def test_omit_empty_attributes_skips_special_and_regular_attributes():
+    tree = etree.fromstring(
+        '<root xmlns:xmi="http://www.omg.org/XMI" xmi:version=""'
+        ' name="Root" description=""/>'
+    )
+    expected = b'<root xmlns:xmi="http://www.omg.org/XMI" name="Root"/>\n'
+
+    actual = exs.serialize(tree, omit_empty_attributes=True, newline="\n")
+
+    assert actual == expected
+
+
+def test_keep_empty_attributes_overrides_omit_empty_attributes():
+    tree = etree.fromstring('<root description="" value=""/>')
+    expected = b'<root value=""/>\n'
+
+    actual = exs.serialize(
+        tree,
+        omit_empty_attributes=True,
+        keep_empty_attributes=["value"],
+        newline="\n",
+    )
+
+    assert actual == expected
+
+
+PRETTY_XML_WITH_BLANKS = """\
+<root name="Root">
+    <child id="child-id" name="Child">
+        <leaf id="leaf-id" name="Leaf">
+        </leaf>
+    </child>
+    <mixed>Some <b>bold</b> text</mixed>
+</root>
+"""
+
+
+def test_normalize_whitespace_reindents_trees_parsed_with_blank_text():
+    tree = etree.fromstring(PRETTY_XML_WITH_BLANKS)
+    expected = (
+        b'<root name="Root">\n'
+        b'  <child id="child-id" name="Child">\n'
+        b'    <leaf id="leaf-id" name="Leaf"/>\n'
+        b"  </child>\n"
+        b"  <mixed>Some <b>bold</b> text</mixed>\n"
+        b"</root>\n"
+    )
+
+    actual = exs.serialize(tree, newline="\n")
+
+    assert actual == expected
+
+
+def test_whitespace_is_written_verbatim_without_normalization():
+    tree = etree.fromstring("<root>\n   <child/>\n</root>")
+    expected = b"<root>\n   <child/>\n</root>\n"
+
+    actual = exs.serialize(tree, normalize_whitespace=False, newline="\n")
+
+    assert actual == expected
+
+
+PRESERVED_XML = """\
+<root>
+  <bodies xml:space="preserve">
+    def main():
+        <b>print</b>(greeting)   <i/>
+
+  </bodies>
+  <pre xml:space="preserve"><a>  <b/>  </a><c xml:space="default">
+      <d/>
+    </c></pre>
+</root>
+"""
+
+
+def test_xml_space_preserve_subtrees_round_trip_verbatim():
+    tree = etree.fromstring(PRESERVED_XML)
+    expected = PRESERVED_XML.encode("utf-8")
+
+    actual = exs.serialize(tree, newline="\n")
+
+    assert actual == expected
+
+
+def test_xml_space_preserve_disables_attribute_wrapping():
+    tree = etree.fromstring(
+        '<root><pre xml:space="preserve"><a one="1111111111"'
+        ' two="2222222222" three="3333333333"/></pre></root>'
+    )
+    expected = (
+        b"<root>\n"
+        b'  <pre xml:space="preserve"><a one="1111111111" two="2222222222"'
+        b' three="3333333333"/></pre>\n'
+        b"</root>\n"
+    )
+
+    actual = exs.serialize(tree, line_length=40, newline="\n")
+
+    assert actual == expected
+
+
+def test_verbatim_tags_serialize_their_subtree_without_reformatting():
+    tree = etree.fromstring(
+        '<root xmlns:svg="http://www.w3.org/2000/svg">'
+        '<svg:svg> <svg:rect width="100" height="100" x="10" y="10"'
+        ' style="fill:blue"/>\n<svg:g xml:space="default"><svg:a/></svg:g>'
+        "</svg:svg><other><svg/></other></root>"
+    )
+    expected = (
+        b'<root xmlns:svg="http://www.w3.org/2000/svg">\n'
+        b'  <svg:svg> <svg:rect width="100" height="100" x="10" y="10"'
+        b' style="fill:blue"/>\n<svg:g xml:space="default"><svg:a/></svg:g>'
+        b"</svg:svg>\n"
+        b"  <other>\n"
+        b"    <svg/>\n"
+        b"  </other>\n"
+        b"</root>\n"
+    )
+
+    actual = exs.serialize(
+        tree,
+        line_length=40,
+        verbatim_tags=["{http://www.w3.org/2000/svg}svg"],
+        newline="\n",
+    )
+
+    assert actual == expected
+
+
+HAND_EDITED_XML = """\
+<root name="Root">
+      <child name="Child">   Some
+    <b>  bold</b>   text
+        </child>
+  <leaf name="Leaf">
+  </leaf>
+          <!-- a comment -->
+</root>
+"""
+CLEAN_XML = (
+    '<root name="Root"><child name="Child"> Some <b> bold</b> text </child>'
+    '<leaf name="Leaf"/><!-- a comment --></root>'
+)
+
+
+def test_reindent_formats_hand_edited_trees_like_clean_ones():
+    messy = etree.fromstring(HAND_EDITED_XML)
+    clean = etree.fromstring(CLEAN_XML)
+    expected = exs.serialize(clean, newline="\n")
+
+    actual = exs.serialize(messy, reindent=True, newline="\n")
+
+    assert actual == expected
+    assert actual == (
+        b'<root name="Root">\n'
+        b'  <child name="Child"> Some <b> bold</b> text </child>\n'
+        b'  <leaf name="Leaf"/>\n'
+        b"  <!-- a comment -->\n"
+        b"</root>\n"
+    )
+
+
+def test_reindent_keeps_inner_whitespace_of_text():
+    tree = etree.fromstring("<root>\n  one  two\n  three\n</root>")
+    expected = b"<root> one  two\n  three </root>\n"
+
+    actual = exs.serialize(tree, reindent=True, newline="\n")
+
+    assert actual == expected
+
+
+def test_multiline_comments_are_indented_to_the_element_level():
+    tree = etree.fromstring(
+        "<root><a><b><!-- first\nsecond\n  third -->"
+        "<c/></b></a></root>"
+    )
+    expected = (
+        b"<root>\n"
+        b"  <a>\n"
+        b"    <b>\n"
+        b"      <!-- first\n"
+        b"      second\n"
+        b"        third -->\n"
+        b"      <c/>\n"
+        b"    </b>\n"
+        b"  </a>\n"
+        b"</root>\n"
+    )
+
+    actual = exs.serialize(tree, newline="\n")
+
+    assert actual == expected
+
+
+def test_reserializing_multiline_comments_does_not_increase_indentation():
+    tree = etree.fromstring(
+        "<root><a><b><!-- first\nsecond\n  third --></b></a></root>"
+    )
+    expected = exs.serialize(tree, newline="\n")
+
+    actual = exs.serialize(etree.fromstring(expected), newline="\n")
+
+    assert actual == expected
+
+
+def test_multiline_comments_next_to_the_root_are_not_indented():
+    tree = etree.fromstring(
+        "<!-- first\n  second\nthird --><root/>"
+    ).getroottree()
+    expected = b"\n<!-- first\n  second\nthird -->\n<root/>\n"
+
+    actual = exs.serialize(tree, newline="\n")
+
+    assert actual == expected
+
+
+@pytest.mark.parametrize(
+    "text",
+    [
+        pytest.param("one\r\ntwo\r\nthree", id="crlf"),
+        pytest.param("one\rtwo\rthree", id="cr"),
+        pytest.param("one\r\ntwo\rthree", id="mixed"),
+        pytest.param("one\ntwo\r\nthree", id="mixed-lf"),
+    ],
+)
+def test_normalize_text_newlines_converts_carriage_returns(text):
+    tree = etree.Element("root")
+    tree.text = text
+    child = etree.SubElement(tree, "child")
+    child.tail = text
+    expected = b"<root>one\ntwo\nthree<child/>one\ntwo\nthree</root>\n"
+
+    actual = exs.serialize(tree, normalize_text_newlines=True, newline="\n")
+
+    assert actual == expected
+
+
+def test_carriage_returns_are_escaped_by_default():
+    tree = etree.Element("root")
+    tree.text = "one\r\ntwo\rthree"
+    expected = b"<root>one&#xD;\ntwo&#xD;three</root>\n"
+
+    actual = exs.serialize(tree, newline="\n")
+
+    assert actual == expected
+
+
+@pytest.mark.parametrize(
+    "max_attrs_per_line,expected",
+    [
+        pytest.param(
+            1,
+            b'<root id="root-id"\n    name="Root"\n    a="1"\n    b="2"/>\n',
+            id="1",
+        ),
+        pytest.param(
+            3,
+            b'<root id="root-id"\n    name="Root" a="1" b="2"/>\n',
+            id="3",
+        ),
+        pytest.param(
+            None,
+            b'<root id="root-id"\n    name="Root" a="1" b="2"/>\n',
+            id="unlimited",
+        ),
+    ],
+)
+def test_max_attrs_per_line_limits_the_attributes_on_each_line(
+    max_attrs_per_line, expected
+):
+    tree = etree.fromstring('<root id="root-id" name="Root" a="1" b="2"/>')
+
+    actual = exs.serialize(
+        tree, max_attrs_per_line=max_attrs_per_line, newline="\n"
+    )
+
+    assert actual == expected
+
+
+def test_max_attrs_per_line_composes_with_line_length():
+    tree = etree.fromstring(
+        '<root><child a="1" bbbbbbbbbb="2" c="3" d="4" e="5" f="6"/></root>'
+    )
+    expected = (
+        b"<root>\n"
+        b'  <child a="1" bbbbbbbbbb="2"\n'
+        b'      c="3" d="4" e="5"\n'
+        b'      f="6"/>\n'
+        b"</root>\n"
+    )
+
+    actual = exs.serialize(
+        tree, line_length=24, max_attrs_per_line=3, newline="\n"
+    )
+
+    assert actual == expected
+
+
+def test_max_attrs_per_line_must_not_be_negative():
+    with pytest.raises(ValueError, match="max_attrs_per_line"):
+        exs.serialize(etree.Element("root"), max_attrs_per_line=-1)
+
+
+def test_dangling_bracket_puts_the_end_of_wrapped_tags_on_its_own_line():
+    tree = etree.fromstring(
+        '<root xmlns:a="http://example.com/a" xmlns:b="http://example.com/b"'
+        ' name="Root"><child name="Child" description="Some description"/>'
+        '<bodies format="text/html" lang="en-US">Text</bodies>'
+        '<short a:name="Short"/></root>'
+    )
+    expected = (
+        b'<root xmlns:a="http://example.com/a"\n'
+        b'    xmlns:b="http://example.com/b"\n'
+        b'    name="Root"\n'
+        b">\n"
+        b'  <child name="Child"\n'
+        b'      description="Some description"\n'
+        b"  />\n"
+        b'  <bodies format="text/html"\n'
+        b'      lang="en-US"\n'
+        b"  >Text</bodies>\n"
+        b'  <short a:name="Short"/>\n'
+        b"</root>\n"
+    )
+
+    actual = exs.serialize(
+        tree, line_length=20, dangling_bracket=True, newline="\n"
+    )
+
+    assert actual == expected
+
+
+def test_dangling_bracket_keeps_empty_expanded_tags_together():
+    tree = etree.fromstring(
+        '<root><bodies format="text/html" lang="en-US"/></root>'
+    )
+    expected = (
+        b"<root>\n"
+        b'  <bodies format="text/html"\n'
+        b'      lang="en-US"\n'
+        b"  ></bodies>\n"
+        b"</root>\n"
+    )
+
+    actual = exs.serialize(
+        tree, line_length=20, dangling_bracket=True, newline="\n"
+    )
+
+    assert actual == expected
+
+
+@pytest.mark.parametrize(
+    "space_before_self_close,expected",
+    [
+        (False, b'<root>\n  <child name="Child"/>\n  <leaf/>\n</root>\n'),
+        (True, b'<root>\n  <child name="Child" />\n  <leaf />\n</root>\n'),
+    ],
+)
+def test_space_before_self_close(space_before_self_close, expected):
+    tree = etree.fromstring('<root><child name="Child"/><leaf/></root>')
+
+    actual = exs.serialize(
+        tree, space_before_self_close=space_before_self_close, newline="\n"
+    )
+
+    assert actual == expected
+
+
+@pytest.mark.parametrize("id_attr", ["id", "xmi:id"])
+def test_keep_id_on_tag_line_does_not_wrap_before_the_id(id_attr):
+    tree = etree.fromstring(
+        '<root xmlns:xmi="http://www.omg.org/XMI"><ownedLongTypeName'
+        f' name="Name" {id_attr}="0123456789abcdef" summary="Summary"/>'
+        "</root>"
+    )
+    expected = (
+        '<root xmlns:xmi="http://www.omg.org/XMI">\n'
+        f'  <ownedLongTypeName name="Name" {id_attr}="0123456789abcdef"\n'
+        '      summary="Summary"/>\n'
+        "</root>\n"
+    ).encode("ascii")
+
+    actual = exs.serialize(
+        tree, line_length=20, keep_id_on_tag_line=True, newline="\n"
+    )
+
+    assert actual == expected
+
+
+def test_format_marker_controls_the_layout_of_single_elements():
+    tree = etree.fromstring(
+        f'<root xmlns:exs="{exs.NS_EXS}">'
+        '<one exs:format="compact" name="One" description="Long text">'
+        '<child name="Child"/></one>'
+        '<two exs:format="expand"><child/></two>'
+        "<three><child/></three>"
+        "</root>"
+    )
+    expected = (
+        b"<root>\n"
+        b'  <one name="One" description="Long text"><child name="Child"/>'
+        b"</one>\n"
+        b"  <two>\n"
+        b"    <child/>\n"
+        b"  </two>\n"
+        b"  <three><child/></three>\n"
+        b"</root>\n"
+    )
+
+    actual = exs.serialize(tree, line_length=30, compact=True, newline="\n")
+
+    assert actual == expected
+
+
+def test_format_namespace_is_declared_if_other_attributes_use_it():
+    tree = etree.fromstring(
+        f'<root xmlns:exs="{exs.NS_EXS}">'
+        '<child exs:format="expand" exs:note="Note"/></root>'
+    )
+    expected = (
+        f'<root xmlns:exs="{exs.NS_EXS}">\n'
+        '  <child exs:note="Note"/>\n'
+        "</root>\n"
+    ).encode()
+
+    actual = exs.serialize(tree, newline="\n")
+
+    assert actual == expected
+    assert etree.fromstring(actual)[0].get(f"{{{exs.NS_EXS}}}note") == "Note"
+
+
+def test_format_marker_with_unknown_value_raises_naming_the_element():
+    tree = etree.Element("root")
+    etree.SubElement(tree, "child", id="child-id").set(
+        exs.FORMAT_MARKER, "sideways"
+    )
+
+    with pytest.raises(ValueError, match="'sideways'.*child-id"):
+        exs.serialize(tree)
+
+
+def test_banner_is_written_after_the_declaration():
+    tree = etree.fromstring("<!-- model --><root/>").getroottree()
+    expected = (
+        b'<?xml version="1.0" encoding="UTF-8"?>\n'
+        b"<!-- Generated file,\n"
+        b"     do not edit -->\n"
+        b"\n<!-- model -->\n"
+        b"<root/>\n"
+    )
+    buffer = io.BytesIO()
+
+    exs.write(
+        tree,
+        buffer,
+        banner=" Generated file,\n     do not edit ",
+        newline="\n",
+    )
+
+    assert buffer.getvalue() == expected
+
+
+@pytest.mark.parametrize("banner", ["a -- b", "ends with a dash-"])
+def test_banner_must_be_a_valid_comment(banner):
+    with pytest.raises(ValueError, match="Banner"):
+        exs.serialize(etree.Element("root"), banner=banner)
+
+
+def test_base_indent_shifts_all_lines_of_the_tree():
+    tree = etree.fromstring(NESTED_XML)
+    unshifted = exs.serialize(tree, newline="\n")
+    expected = b"".join(
+        b"    " + line for line in unshifted.splitlines(keepends=True)
+    )
+
+    actual = exs.serialize(tree, base_indent=2, newline="\n")
+
+    assert actual == expected
+
+
+def test_base_indent_does_not_count_towards_the_line_length():
+    tree = etree.fromstring(
+        '<root><child name="Child" description="Description"/></root>'
+    )
+    unshifted = exs.serialize(tree, line_length=30, newline="\n")
+    expected = b"".join(
+        b" " * 40 + line for line in unshifted.splitlines(keepends=True)
+    )
+
+    actual = exs.serialize(tree, line_length=30, base_indent=20, newline="\n")
+
+    assert actual == expected
+
+
+def test_blank_line_between_root_children():
+    tree = etree.fromstring(
+        "<root><one><a/><b/></one><!-- two --><two/><three/></root>"
+    )
+    expected = (
+        b"<root>\n"
+        b"  <one>\n"
+        b"    <a/>\n"
+        b"    <b/>\n"
+        b"  </one>\n"
+        b"\n"
+        b"  <!-- two -->\n"
+        b"  <two/>\n"
+        b"\n"
+        b"  <three/>\n"
+        b"</root>\n"
+    )
+
+    actual = exs.serialize(
+        tree, blank_line_between_root_children=True, newline="\n"
+    )
+    reserialized = exs.serialize(
+        etree.fromstring(actual),
+        blank_line_between_root_children=True,
+        newline="\n",
+    )
+
+    assert actual == expected
+    assert reserialized == expected
+
+
+def test_minify_writes_the_tree_without_line_breaks():
+    tree = etree.fromstring(
+        "<root><child>Text &amp; more<b>bold</b></child>"
+        '<bodies/><!-- comment --><leaf name="Leaf"/></root>'
+    )
+    expected = (
+        b'<?xml version="1.0" encoding="UTF-8"?><root>'
+        b"<child>Text &amp; more<b>bold</b></child>"
+        b'<bodies/><!-- comment --><leaf name="Leaf"/></root>'
+    )
+    buffer = io.BytesIO()
+
+    exs.write(tree, buffer, minify=True, line_length=10, attr_per_line=True)
+
+    assert buffer.getvalue() == expected
+    assert b"\n" not in buffer.getvalue()
+
+
+def test_minify_output_parses_back_to_an_equal_tree():
+    tree = etree.parse(str(CAPELLA_FILE)).getroot()
+
+    actual = exs.serialize(tree, minify=True)
+
+    assert exs.serialize(etree.fromstring(actual)) == exs.serialize(tree)
+
+
+@pytest.mark.parametrize(
+    "preset,filename",
+    [
+        ("capella", "Melody Model Test.capella"),
+        ("aird", "Melody Model Test.aird"),
+        ("afm", "Melody Model Test.afm"),
+    ],
+)
+def test_presets_reproduce_the_files_of_the_test_model(preset, filename):
+    path = TEST_ROOT / "5_0" / filename
+    expected = path.read_bytes()
+    buffer = io.BytesIO()
+
+    exs.write(etree.parse(str(path)), buffer, preset=preset, newline="\n")
+
+    assert buffer.getvalue() == expected
+
+
+def test_keyword_arguments_override_preset_options():
+    tree = etree.fromstring(NESTED_XML)
+    buffer = io.BytesIO()
+
+    exs.write(tree, buffer, preset="aird", line_length=10, declaration=False)
+
+    assert buffer.getvalue() == exs.serialize(tree, line_length=10)
+
+
+def test_unknown_presets_are_rejected():
+    with pytest.raises(ValueError, match="preset"):
+        exs.write(etree.Element("root"), io.BytesIO(), preset="odesign")
+
+
+def test_front_attributes_are_written_first_in_the_given_order():
+    tree = etree.fromstring(
+        '<root xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance">'
+        '<child name="Child" xsi:nil="true" href="#ref"/></root>'
+    )
+    expected = (
+        b'<root xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance">\n'
+        b'  <child href="#ref" xsi:nil="true" name="Child"/>\n'
+        b"</root>\n"
+    )
+
+    actual = exs.serialize(
+        tree,
+        front_attributes=[
+            "href",
+            "{http://www.w3.org/2001/XMLSchema-instance}nil",
+        ],
+        newline="\n",
+    )
+
+    assert actual == expected
+
+
+def test_sort_children_makes_the_order_of_children_deterministic():
+    first = etree.fromstring(
+        '<root xmlns:xmi="http://www.omg.org/XMI"><ownedExtensions>'
+        '<ext xmi:id="b"/><ext name="no-id"/><ext xmi:id="a"/>'
+        "</ownedExtensions><other><c/><b/></other></root>"
+    )
+    second = etree.fromstring(
+        '<root xmlns:xmi="http://www.omg.org/XMI"><ownedExtensions>'
+        '<ext xmi:id="a"/><ext name="no-id"/><ext xmi:id="b"/>'
+        "</ownedExtensions><other><c/><b/></other></root>"
+    )
+    sort_children = {"ownedExtensions": "{http://www.omg.org/XMI}id"}
+    expected = (
+        b'<root xmlns:xmi="http://www.omg.org/XMI">\n'
+        b"  <ownedExtensions>\n"
+        b'    <ext xmi:id="a"/>\n'
+        b'    <ext xmi:id="b"/>\n'
+        b'    <ext name="no-id"/>\n'
+        b"  </ownedExtensions>\n"
+        b"  <other>\n"
+        b"    <c/>\n"
+        b"    <b/>\n"
+        b"  </other>\n"
+        b"</root>\n"
+    )
+
+    actual_first = exs.serialize(
+        first, sort_children=sort_children, newline="\n"
+    )
+    actual_second = exs.serialize(
+        second, sort_children=sort_children, newline="\n"
+    )
+
+    assert actual_first == actual_second == expected
+    original_order = [i.get("{http://www.omg.org/XMI}id") for i in first[0]]
+    assert original_order == ["b", None, "a"]
+
+
+def test_sort_children_keeps_comments_in_front_of_the_next_child():
+    tree = etree.fromstring(
+        '<root><!-- about c --><c name="c"/><b name="b"/>'
+        '<!-- about a --><a name="a"/><!-- end --></root>'
+    )
+    expected = (
+        b"<root>\n"
+        b"  <!-- about a -->\n"
+        b'  <a name="a"/>\n'
+        b'  <b name="b"/>\n'
+        b"  <!-- about c -->\n"
+        b'  <c name="c"/>\n'
+        b"  <!-- end -->\n"
+        b"</root>\n"
+    )
+
+    actual = exs.serialize(tree, sort_children={"root": "name"}, newline="\n")
+
+    assert actual == expected
+
+
+def test_default_namespaces_are_written_without_prefix():
+    expected = DEFAULT_NS_FILE.read_bytes()
+    buffer = io.BytesIO()
+
+    exs.write(etree.parse(str(DEFAULT_NS_FILE)), buffer, preset="afm")
+
+    assert buffer.getvalue() == expected
+
+
+def test_default_namespace_is_preferred_over_prefixes_for_tags():
+    tree = etree.fromstring(
+        '<root xmlns="urn:a" xmlns:a="urn:a"><child a:name="Child"/></root>'
+    )
+    expected = (
+        b'<root xmlns="urn:a" xmlns:a="urn:a">\n'
+        b'  <child a:name="Child"/>\n'
+        b"</root>\n"
+    )
+
+    actual = exs.serialize(tree, newline="\n")
+
+    assert actual == expected
+
+
+def test_attributes_never_use_the_default_namespace():
+    tree = etree.Element("{urn:a}root", nsmap={None: "urn:a"})
+    tree.set("{urn:a}name", "Root")
+    expected = b'<root xmlns="urn:a" xmlns:ns0="urn:a" ns0:name="Root"/>\n'
+
+    actual = exs.serialize(tree, newline="\n")
+
+    assert actual == expected
+
+
+def test_unqualified_tags_inside_a_default_namespace_are_rejected():
+    tree = etree.Element("{urn:a}root", nsmap={None: "urn:a"})
+    etree.SubElement(tree, "child")
+
+    with pytest.raises(ValueError, match="'child'"):
+        exs.serialize(tree)
+
+
+def _with_nsmap(tag, nsmap):
+    """Create an element that reports ``nsmap`` as its namespaces.
+
+    lxml always declares the namespaces that an element uses, but
+    custom element classes can report any ``nsmap``.  The element must
+    be kept referenced while it is in use, otherwise lxml creates a
+    plain element for it again.
+    """
+
+    class Element(etree.ElementBase):
+        TAG = tag
+
+        @property
+        def nsmap(self):
+            return nsmap
+
+    return Element()
+
+
+def test_undeclared_namespaces_raise_an_error_pointing_to_the_element():
+    tree = etree.Element("root", {"{http://www.omg.org/XMI}id": "root-id"})
+    container = etree.SubElement(tree, "container")
+    child = _with_nsmap("{urn:a}child", {})
+    container.append(child)
+
+    with pytest.raises(ValueError) as excinfo:
+        exs.serialize(tree)
+
+    message = str(excinfo.value)
+    assert "'urn:a'" in message
+    assert "'child'" in message
+    assert "root-id" in message
+
+
+def test_early_namespaces_restore_the_order_of_aird_root_elements():
+    aird_file = TEST_ROOT / "5_0" / "Melody Model Test.aird"
+    captured = aird_file.read_bytes()
+    start = captured.index(b"<xmi:XMI")
+    expected = captured[start : captured.index(b">", start) + 1]
+    root = etree.parse(str(aird_file)).getroot()
+    early_namespaces = list(root.nsmap.values())
+    tree = etree.Element(root.tag, nsmap=dict(reversed(root.nsmap.items())))
+    for key, value in root.items():
+        tree.set(key, value)
+    etree.SubElement(tree, "ownedViews")
+
+    actual = exs.serialize(
+        tree, line_length=None, early_namespaces=early_namespaces
+    )
+
+    assert actual.startswith(expected)
+
+
+def test_hoist_namespaces_declares_all_namespaces_on_the_root():
+    capella_file = CAPELLA_FILE.read_bytes()
+    start = capella_file.index(b"<org.polarsys.capella.core.data.")
+    expected = capella_file[start : capella_file.index(b">", start) + 1]
+    root = etree.parse(str(CAPELLA_FILE)).getroot()
+    namespaces = list(root.nsmap.items())
+    tree = etree.Element(root.tag, nsmap=dict(namespaces[:8]))
+    for key, value in root.items():
+        tree.set(key, value)
+    child = etree.SubElement(
+        tree, "ownedModelRoots", nsmap=dict(namespaces[8:])
+    )
+    etree.SubElement(child, "ownedExtensions", nsmap=dict(namespaces[3:9]))
+
+    actual = exs.serialize(tree, hoist_namespaces=True)
+
+    assert actual.startswith(expected)
+    assert actual.count(b"xmlns:") == len(namespaces)
+
+
+def test_hoist_namespaces_rejects_conflicting_prefixes():
+    tree = etree.Element("root")
+    etree.SubElement(tree, "{urn:a}one", nsmap={"p": "urn:a"})
+    etree.SubElement(tree, "{urn:b}two", nsmap={"p": "urn:b"})
+
+    with pytest.raises(ValueError, match="'p'.*'urn:a'.*'urn:b'"):
+        exs.serialize(tree, hoist_namespaces=True)
+
+
+def test_xmlns_sort_by_alias_restores_the_order_of_capella_files():
+    capella_file = CAPELLA_FILE.read_bytes()
+    start = capella_file.index(b"<org.polarsys.capella.core.data.")
+    expected = capella_file[start : capella_file.index(b">", start) + 1]
+    root = etree.parse(str(CAPELLA_FILE)).getroot()
+    tree = etree.Element(root.tag, nsmap=dict(reversed(root.nsmap.items())))
+    for key, value in root.items():
+        tree.set(key, value)
+    etree.SubElement(tree, "ownedModelRoots")
+
+    actual = exs.serialize(
+        tree,
+        xmlns_sort="alias",
+        early_namespaces=[exs.NS_XMI, exs.NS_XSI],
+    )
+
+    assert actual.startswith(expected)
+
+
+def test_xmlns_sort_by_uri_honors_early_namespaces():
+    tree = etree.Element(
+        "root",
+        nsmap={"a": "urn:z", "b": "urn:y", "x": "urn:early", "c": "urn:x"},
+    )
+    expected = (
+        b'<root xmlns:x="urn:early" xmlns:c="urn:x" xmlns:b="urn:y"'
+        b' xmlns:a="urn:z"/>'
+    )
+
+    actual = exs.serialize(
+        tree,
+        line_length=None,
+        xmlns_sort="uri",
+        early_namespaces=["urn:early"],
+        trailing_newline=False,
+    )
+
+    assert actual == expected
+
+
+def test_normalize_well_known_prefixes_restores_canonical_prefixes():
+    capella_file = CAPELLA_FILE.read_bytes()
+    start = capella_file.index(b"<org.polarsys.capella.core.data.")
+    expected = capella_file[start : capella_file.index(b">", start) + 1]
+    root = etree.parse(str(CAPELLA_FILE)).getroot()
+    renamed = {"xmi": "xmi2", "xsi": "ns0"}
+    tree = etree.Element(
+        root.tag, nsmap={renamed.get(k, k): v for k, v in root.nsmap.items()}
+    )
+    for key, value in root.items():
+        tree.set(key, value)
+    etree.SubElement(tree, "ownedModelRoots")
+
+    actual = exs.serialize(tree, normalize_well_known_prefixes=True)
+
+    assert actual.startswith(expected)
+    assert b"xmi2" not in actual
+    assert b"ns0" not in actual
+
+
+def test_normalize_well_known_prefixes_rejects_rebound_prefixes():
+    tree = etree.Element(
+        "root", nsmap={"xmi": "urn:not-xmi", "xmi2": exs.NS_XMI}
+    )
+
+    with pytest.raises(ValueError, match="'xmi'"):
+        exs.serialize(tree, normalize_well_known_prefixes=True)
+
+
+def test_prune_unused_namespaces_leaves_out_unused_declarations():
+    tree = etree.fromstring(UNUSED_NAMESPACES_XML)
+    expected = (
+        b'<root xmlns:used="urn:used">\n'
+        b'  <child used:name="Child"/>\n'
+        b"</root>\n"
+    )
+
+    actual = exs.serialize(tree, prune_unused_namespaces=True, newline="\n")
+
+    assert actual == expected
+
+
+def test_prune_unused_namespaces_keeps_namespaces_used_by_type_values():
+    tree = etree.fromstring(
+        '<root xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance"'
+        ' xmlns:typed="urn:typed"><child xsi:type="typed:Type"/></root>'
+    )
+    expected = (
+        b'<root xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance"'
+        b' xmlns:typed="urn:typed">\n'
+        b'  <child xsi:type="typed:Type"/>\n'
+        b"</root>\n"
+    )
+
+    actual = exs.serialize(
+        tree, line_length=None, prune_unused_namespaces=True, newline="\n"
+    )
+
+    assert actual == expected
+
+
+def test_prefix_map_rewrites_prefixes_to_the_preferred_ones():
+    tree = etree.fromstring(
+        '<root xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance"'
+        ' xmlns:capellacore_1="urn:capellacore">'
+        '<capellacore_1:child xsi:type="capellacore_1:Type"/></root>'
+    )
+    expected = (
+        b'<root xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance"'
+        b' xmlns:capellacore="urn:capellacore">\n'
+        b'  <capellacore:child xsi:type="capellacore:Type"/>\n'
+        b"</root>\n"
+    )
+
+    actual = exs.serialize(
+        tree,
+        line_length=None,
+        prefix_map={"urn:capellacore": "capellacore"},
+        newline="\n",
+    )
+
+    assert actual == expected
+    reparsed = etree.fromstring(actual)
+    assert reparsed[0].tag == tree[0].tag
+    assert reparsed[0].nsmap["capellacore"] == "urn:capellacore"
+
+
+def test_prefix_map_raises_on_collisions_with_bound_prefixes():
+    tree = etree.fromstring(
+        '<root xmlns:a="urn:a" xmlns:b="urn:b"><a:child/></root>'
+    )
+
+    with pytest.raises(ValueError, match="Cannot bind prefix 'a'"):
+        exs.serialize(tree, prefix_map={"urn:b": "a"})
+
+
+def test_new_alias_for_an_inherited_namespace_is_declared():
+    root = etree.Element("{urn:x}root", nsmap={"a": "urn:x"})
+    child = etree.SubElement(root, "{urn:x}child", nsmap={"b": "urn:x"})
+    etree.SubElement(child, "{urn:x}grandchild")
+
+    actual = exs.serialize(root, newline="\n")
+
+    assert b' xmlns:b="urn:x"' in actual
+    reparsed = etree.fromstring(actual)
+    assert [i.tag for i in reparsed.iter()] == [i.tag for i in root.iter()]
+
+
+def test_shadowed_prefixes_are_declared_at_every_level():
+    root = etree.Element("{urn:a}root", nsmap={"a": "urn:a"})
+    child = etree.SubElement(root, "{urn:b}child", nsmap={"a": "urn:b"})
+    etree.SubElement(child, "{urn:a}grandchild", nsmap={"a": "urn:a"})
+    expected = (
+        b'<a:root xmlns:a="urn:a">\n'
+        b'  <a:child xmlns:a="urn:b">\n'
+        b'    <a:grandchild xmlns:a="urn:a"/>\n'
+        b"  </a:child>\n"
+        b"</a:root>\n"
+    )
+
+    actual = exs.serialize(root, newline="\n")
+
+    assert actual == expected
+    reparsed = etree.fromstring(actual)
+    assert [i.tag for i in reparsed.iter()] == [i.tag for i in root.iter()]
+
+
+def test_namespaces_made_unreachable_by_shadowing_raise_an_error():
+    root = etree.Element("{urn:a}root", nsmap={"a": "urn:a"})
+    child = etree.SubElement(root, "{urn:b}child", nsmap={"a": "urn:b"})
+    grandchild = etree.SubElement(
+        child, "{urn:c}grandchild", nsmap={"a": "urn:c"}
+    )
+    # Only the bindings in scope at the grandchild, without the one
+    # that lxml would add for the leaf's own namespace.
+    leaf = _with_nsmap("{urn:a}leaf", dict(grandchild.nsmap))
+    grandchild.append(leaf)
+
+    with pytest.raises(ValueError) as excinfo:
+        exs.serialize(root)
+
+    message = str(excinfo.value)
+    assert "'urn:a' of 'leaf' is unreachable" in message
+    assert "prefix 'a' is rebound to 'urn:c'" in message
+
+
+@pytest.mark.parametrize(
+    ["prefix_choice", "expected_prefix"],
+    [
+        pytest.param("last-declared", "a", id="last-declared"),
+        pytest.param("first-declared", "b", id="first-declared"),
+        pytest.param("alphabetical", "a", id="alphabetical"),
+        pytest.param(["c", "a"], "c", id="preference"),
+    ],
+)
+def test_prefix_choice_selects_among_aliases_for_the_same_uri(
+    prefix_choice, expected_prefix
+):
+    tree = etree.Element(
+        "{urn:x}root", nsmap={"b": "urn:x", "c": "urn:x", "a": "urn:x"}
+    )
+    etree.SubElement(tree, "{urn:x}child")
+    expected = (
+        f'<{expected_prefix}:root xmlns:b="urn:x" xmlns:c="urn:x"'
+        ' xmlns:a="urn:x">\n'
+        f"  <{expected_prefix}:child/>\n"
+        f"</{expected_prefix}:root>\n"
+    ).encode()
+
+    actual = exs.serialize(
+        tree, line_length=None, prefix_choice=prefix_choice, newline="\n"
+    )
+
+    assert actual == expected
+
+
+def test_the_last_declared_prefix_for_a_uri_is_used_by_default():
+    tree = etree.Element(
+        "{urn:x}root", nsmap={"b": "urn:x", "c": "urn:x", "a": "urn:x"}
+    )
+    etree.SubElement(tree, "{urn:x}child")
+    expected = (
+        b'<a:root xmlns:b="urn:x" xmlns:c="urn:x" xmlns:a="urn:x">\n'
+        b"  <a:child/>\n"
+        b"</a:root>\n"
+    )
+
+    actual = exs.serialize(tree, newline="\n")
+
+    assert actual == expected
+
+
+def test_undeclared_attribute_namespaces_raise_an_error():
+    tree = etree.Element("root")
+    child = _with_nsmap("child", {})
+    child.set("{urn:a}name", "Child")
+    tree.append(child)
+
+    with pytest.raises(ValueError, match="'urn:a' of 'name'.*'child'"):
+        exs.serialize(tree)
+
+
+def test_declare_missing_namespaces_generates_prefixes():
+    tree = etree.Element("root")
+    child = _with_nsmap("child", {})
+    child.set("{urn:a}name", "Child")
+    tree.append(child)
+    expected = (
+        b"<root>\n"
+        b'  <child xmlns:ns0="urn:a" ns0:name="Child"/>\n'
+        b"</root>\n"
+    )
+
+    actual = exs.serialize(tree, declare_missing_namespaces=True, newline="\n")
+
+    assert actual == expected
+
+
+def test_undeclared_default_namespaces_are_kept():
+    xml = (
+        '<root xmlns="urn:a">\n'
+        '  <child xmlns="">\n'
+        "    <leaf/>\n"
+        "  </child>\n"
+        "  <sibling/>\n"
+        "</root>\n"
+    )
+    tree = etree.fromstring(xml)
+
+    actual = exs.serialize(tree, newline="\n")
+
+    assert actual == xml.encode()
+    reparsed = etree.fromstring(actual)
+    assert [i.tag for i in reparsed.iter()] == [i.tag for i in tree.iter()]
+
+
+def test_xml_namespace_attributes_use_the_implicit_xml_prefix():
+    tree = etree.Element("root")
+    child = etree.SubElement(tree, "child")
+    child.set("{http://www.w3.org/XML/1998/namespace}space", "preserve")
+    child.set("{http://www.w3.org/XML/1998/namespace}lang", "en")
+    expected = (
+        b"<root>\n"
+        b'  <child xml:space="preserve" xml:lang="en"/>\n'
+        b"</root>\n"
+    )
+
+    actual = exs.serialize(tree, newline="\n")
+
+    assert actual == expected
+    assert b"xmlns:xml" not in actual
+    reparsed = etree.fromstring(actual)
+    assert dict(reparsed[0].items()) == dict(child.items())
+
+
+def test_anchor_schema_location_writes_it_after_the_xmlns_declarations():
+    tree = etree.Element(
+        "{urn:model}Model",
+        nsmap={"model": "urn:model", "xsi": exs.NS_XSI},
+    )
+    tree.set("name", "Model")
+    tree.set(
+        f"{{{exs.NS_XSI}}}schemaLocation",
+        "urn:model https://example.com/schemas/model/1.0/model.xsd",
+    )
+    expected = (
+        b'<model:Model xmlns:model="urn:model"'
+        b' xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance"\n'
+        b'    xsi:schemaLocation="urn:model'
+        b' https://example.com/schemas/model/1.0/model.xsd"\n'
+        b'    name="Model"/>\n'
+    )
+
+    actual = exs.serialize(tree, anchor_schema_location=True, newline="\n")
+
+    assert actual == expected
+
+
+def test_qname_tags_and_attribute_names_are_supported():
+    tree = etree.Element(
+        etree.QName("urn:model", "Model"), nsmap={"model": "urn:model"}
+    )
+    child = etree.SubElement(tree, etree.QName("urn:model", "child"))
+    child.set(etree.QName("urn:model", "name"), "Child")
+    child.set(etree.QName("id"), "child-id")
+    expected = (
+        b'<model:Model xmlns:model="urn:model">\n'
+        b'  <model:child model:name="Child" id="child-id"/>\n'
+        b"</model:Model>\n"
+    )
+
+    actual = exs.serialize(tree, newline="\n")
+
+    assert actual == expected
+
+
+def test_qnames_are_accepted_as_attribute_names_in_options():
+    tree = etree.Element("root", {"a": "1", "b": "2", "c": "3"})
+
+    actual = exs.serialize(
+        tree,
+        drop_attributes=[etree.QName("b")],
+        front_attributes=[etree.QName("c")],
+        trailing_newline=False,
+    )
+
+    assert actual == b'<root c="3" a="1"/>'
+
+
+@pytest.mark.parametrize(
+    "prefix",
+    [
+        pytest.param("1bad", id="digits-first"),
+        pytest.param("a b", id="space"),
+        pytest.param("-dash", id="dash-first"),
+        pytest.param("abc\n", id="trailing-newline"),
+    ],
+)
+def test_invalid_prefixes_raise_an_error(prefix):
+    tree = etree.Element("{urn:a}root", nsmap={"a": "urn:a"})
+
+    pattern = f"prefix {re.escape(repr(prefix))}.*root'"
+    with pytest.raises(ValueError, match=pattern):
+        exs.serialize(tree, prefix_map={"urn:a": prefix})
+
+
+@pytest.mark.parametrize(
+    "prefix",
+    [
+        pytest.param("a:b", id="colon"),
+        pytest.param("1bad", id="digits-first"),
+        pytest.param("abc\n", id="trailing-newline"),
+    ],
+)
+def test_invalid_prefixes_in_the_nsmap_raise_an_error(prefix):
+    tree = etree.Element("root", {"id": "root-id"})
+    child = _with_nsmap("child", {prefix: "urn:a"})
+    tree.append(child)
+
+    pattern = (
+        f"Invalid namespace prefix {re.escape(repr(prefix))}"
+        " declared at element 'child' below element 'root' .id=root-id."
+    )
+    with pytest.raises(ValueError, match=pattern):
+        exs.serialize(tree)
+
+
+def test_prefixes_with_unicode_letters_are_valid():
+    tree = etree.Element("{urn:a}root", nsmap={"a": "urn:a"})
+
+    actual = exs.serialize(
+        tree, prefix_map={"urn:a": "präfix"}, trailing_newline=False
+    )
+
+    assert actual == '<präfix:root xmlns:präfix="urn:a"/>'.encode()
+
+
+def test_validate_names_false_skips_the_prefix_check():
+    tree = etree.Element("{urn:a}root", nsmap={"a": "urn:a"})
+
+    actual = exs.serialize(
+        tree,
+        prefix_map={"urn:a": "1bad"},
+        validate_names=False,
+        trailing_newline=False,
+    )
+
+    assert actual == b'<1bad:root xmlns:1bad="urn:a"/>'
+
+
+def test_collect_namespaces_counts_tags_and_attributes_per_namespace():
+    tree = etree.fromstring(
+        '<a:root xmlns:a="urn:a" xmlns:b="urn:b" b:name="Root">'
+        '<b:child xmlns:a="urn:c" a:name="Child" xml:lang="en">'
+        '<grandchild xmlns="urn:b" xmlns:d="urn:a"><d:leaf/></grandchild>'
+        "</b:child></a:root>"
+    )
+
+    actual = exs.collect_namespaces(tree)
+
+    assert actual == {
+        "urn:a": ({"a", "d"}, 2),
+        "urn:b": ({"b", None}, 3),
+        "urn:c": ({"a"}, 1),
+        "http://www.w3.org/XML/1998/namespace": ({"xml"}, 1),
+    }
+
+
+def test_collect_namespaces_counts_the_prefix_that_is_written():
+    tree = etree.fromstring(
+        '<root xmlns:a="urn:a" xmlns:b="urn:a" a:name="Root"><a:child/></root>'
+    )
+
+    actual = exs.collect_namespaces(tree)
+
+    output = exs.serialize(tree, trailing_newline=False)
+    assert b'b:name="Root"' in output
+    assert b"<b:child/>" in output
+    assert actual == {"urn:a": ({"b"}, 2)}
+
+
+def test_check_namespace_conflicts_raises_for_different_versions():
+    tree = etree.Element(
+        "root", {"id": "root-id"}, nsmap={"core": NS_CAPELLACORE_5_0}
+    )
+    etree.SubElement(
+        tree, "child", {"id": "child-id"}, nsmap={"core14": NS_CAPELLACORE_1_4}
+    )
+
+    with pytest.raises(ValueError) as excinfo:
+        exs.serialize(tree, check_namespace_conflicts=True)
+
+    message = str(excinfo.value)
+    assert f"{NS_CAPELLACORE_1_4!r} at element 'child'" in message
+    assert f"{NS_CAPELLACORE_5_0!r} at element 'root'" in message
+
+
+def test_check_namespace_conflicts_warns_about_trailing_slashes():
+    tree = etree.Element("root", nsmap={"a": "http://example.com/ns"})
+    etree.SubElement(tree, "child", nsmap={"b": "http://example.com/ns/"})
+
+    with pytest.warns(UserWarning, match="'http://example.com/ns/'") as record:
+        exs.serialize(tree, check_namespace_conflicts="warn")
+
+    assert record[0].filename == __file__
+
+
+def test_check_namespace_conflicts_accepts_different_metamodels():
+    tree = etree.Element(
+        "root",
+        nsmap={
+            "core": NS_CAPELLACORE_5_0,
+            "common": "http://www.polarsys.org/capella/core/common/5.0.0",
+            "xmi": exs.NS_XMI,
+        },
+    )
+
+    exs.serialize(tree, check_namespace_conflicts=True)
+
+
+@pytest.mark.parametrize(
+    "text",
+    [
+        pytest.param('Say "<hi>" & wave', id="markup"),
+        pytest.param("first\nsecond\tthird\x7f", id="control-characters"),
+    ],
+)
+def test_escape_matches_the_serialized_attribute_values(text):
+    tree = etree.Element("root", {"value": text})
+    serialized = exs.serialize(tree, line_length=None, trailing_newline=False)
+
+    actual = exs.escape(text, context="attribute")
+
+    assert serialized == f'<root value="{actual}"/>'.encode()
+
+
+def test_escape_matches_the_serialized_text_and_comments():
+    tree = etree.Element("root")
+    tree.text = "a < b\nc & d"
+    tree.append(etree.Comment("x -> y"))
+    expected = (
+        f"<root>{exs.escape(tree.text, context='text')}"
+        f"<!--{exs.escape('x -> y', context='comment')}-->\n</root>"
+    ).encode()
+
+    actual = exs.serialize(tree, newline="\n", trailing_newline=False)
+
+    assert actual == expected
+
+
+@pytest.mark.parametrize(
+    ["ncr_style", "newline_reference"],
+    [
+        pytest.param("hex", b"&#xA;", id="hex"),
+        pytest.param("decimal", b"&#10;", id="decimal"),
+    ],
+)
+def test_ncr_style_selects_the_numeric_character_references(
+    ncr_style, newline_reference
+):
+    captured = (
+        b'description="&lt;p>Actors with this capability'
+        b' are able to ingest edibles.&lt;/p>&#xA;"'
+    )
+    assert captured in CAPELLA_FILE.read_bytes()
+    attributes = etree.fromstring(b"<x " + captured + b"/>").attrib
+    tree = etree.Element("root", attributes)
+    expected = captured.replace(b"&#xA;", newline_reference)
+
+    actual = exs.serialize(tree, line_length=None, ncr_style=ncr_style)
+
+    assert expected in actual
+
+
+@pytest.mark.parametrize(
+    ["text", "escaped"],
+    [
+        pytest.param("]]> start", b"]]&gt; start", id="start"),
+        pytest.param("a ]]> b", b"a ]]&gt; b", id="middle"),
+        pytest.param("end ]]>", b"end ]]&gt;", id="end"),
+        pytest.param("a > b ]> c", b"a > b ]> c", id="no-cdata-end"),
+    ],
+)
+def test_cdata_end_markers_are_escaped_in_text_and_tails(text, escaped):
+    tree = etree.Element("root")
+    child = etree.SubElement(tree, "child")
+    child.text = child.tail = text
+    expected = b"<root>\n  <child>%s</child>%s</root>" % (escaped, escaped)
+
+    actual = exs.serialize(tree, newline="\n", trailing_newline=False)
+
+    assert actual == expected
+    assert etree.fromstring(actual)[0].text == text
+
+
+@pytest.mark.parametrize(
+    ["invalid_chars", "expected"],
+    [
+        pytest.param("replace", "a\ufffdb\ufffdc", id="replace"),
+        pytest.param("strip", "abc", id="strip"),
+        pytest.param("reference", "a&#x0;b&#xB;c", id="reference"),
+    ],
+)
+def test_escape_handles_invalid_characters_by_policy(invalid_chars, expected):
+    actual = exs.escape("a\x00b\x0bc", invalid_chars=invalid_chars)
+
+    assert actual == expected
+
+
+def test_escape_rejects_invalid_characters_by_default():
+    with pytest.raises(ValueError, match=r"Invalid character '\\x0b'"):
+        exs.escape("vertical\x0btab")
+
+
+class _InvalidTextElement(etree.ElementBase):
+    TAG = "child"
+
+    @property
+    def text(self):
+        return "pasted\x0cfrom a PDF"
+
+
+def test_invalid_characters_raise_an_error_naming_the_element():
+    tree = etree.Element("root", {"id": "root-id"})
+    child = _InvalidTextElement()
+    tree.append(child)
+
+    with pytest.raises(ValueError, match=r"'\\x0c'.*'child' below.*root-id"):
+        exs.serialize(tree)
+
+
+def test_escape_allows_control_character_references_in_xml_1_1():
+    actual = exs.escape("a\x01b\x85c\u2028d", xml_version="1.1")
+
+    assert actual == "a&#x1;b&#x85;c&#x2028;d"
+
+
+def test_escape_rejects_null_characters_even_in_xml_1_1():
+    with pytest.raises(ValueError, match=r"'\\x00'"):
+        exs.escape("a\x00b", xml_version="1.1")
+
+
+@pytest.mark.parametrize("xml_version", ["1.0", "1.1"])
+def test_xml_version_is_declared_and_output_can_be_parsed(xml_version):
+    tree = etree.Element("root", {"name": "Root \x85\u2028"})
+    etree.SubElement(tree, "child").text = "Text \x85\u2028"
+
+    actual = exs.serialize(tree, declaration=True, xml_version=xml_version)
+
+    assert actual.startswith(f'<?xml version="{xml_version}"'.encode())
+    reparsed = etree.fromstring(actual)
+    assert reparsed.get("name") == "Root \x85\u2028"
+    assert reparsed[0].text == "Text \x85\u2028"
+    if xml_version == "1.1":
+        assert actual.count(b"&#x85;&#x2028;") == 2
+
+
+def test_xml_1_1_output_escapes_c0_control_characters():
+    tree = etree.Element("root")
+    child = _with_raw_values("child", text="a\x01b", name="c\x1fd")
+    tree.append(child)
+
+    actual = exs.serialize(tree, declaration=True, xml_version="1.1")
+
+    assert b'<child name="c&#x1F;d">a&#x1;b</child>' in actual
+
+
+def test_xml_version_must_match_the_declared_version():
+    tree = etree.Element("root")
+
+    with pytest.raises(ValueError, match="'1.1'.*XML 1.0"):
+        exs.serialize(tree, declaration={"version": "1.1"}, xml_version="1.0")
+
+
+class _SurrogateAttributeElement(etree.ElementBase):
+    TAG = "child"
+
+    def keys(self):
+        return ["name"]
+
+    def items(self):
+        return [("name", "broken \udcff")]
+
+
+def test_lone_surrogates_raise_an_error_naming_the_attribute():
+    tree = etree.Element("root", {"id": "root-id"})
+    child = _SurrogateAttributeElement()
+    tree.append(child)
+
+    with pytest.raises(ValueError, match="'name' of element 'child' below"):
+        exs.serialize(tree)
+
+
+def test_lone_surrogates_are_replaced_with_errors_replace():
+    tree = etree.Element("root")
+    child = _SurrogateAttributeElement()
+    tree.append(child)
+
+    actual = exs.serialize(tree, errors="replace", newline="\n")
+
+    assert actual == b'<root>\n  <child name="broken ?"/>\n</root>\n'
+
+
+@pytest.mark.parametrize(
+    ["text", "escaped"],
+    [
+        pytest.param("Grüße", b"Gr&#xFC;&#xDF;e", id="umlauts"),
+        pytest.param("模型", b"&#x6A21;&#x578B;", id="cjk"),
+        pytest.param("🚀 launch", b"&#x1F680; launch", id="emoji"),
+    ],
+)
+def test_ascii_only_escapes_non_ascii_characters(text, escaped):
+    tree = etree.Element("root", {"name": text})
+    tree.text = text
+    expected = b'<root name="%s">%s</root>' % (escaped, escaped)
+
+    actual = exs.serialize(tree, ascii_only=True, trailing_newline=False)
+
+    assert actual == expected
+    assert actual.isascii()
+    reparsed = etree.fromstring(actual)
+    assert (reparsed.get("name"), reparsed.text) == (text, text)
+
+
+def test_ascii_only_measures_line_length_after_escaping():
+    tree = etree.Element("root", {"a": "–" * 8, "b": "x"})
+    expected = b'<root a="%s"\n    b="x"/>' % (b"&#x2013;" * 8)
+
+    actual = exs.serialize(
+        tree,
+        line_length=60,
+        count_escapes=False,
+        ascii_only=True,
+        newline="\n",
+        trailing_newline=False,
+    )
+
+    assert actual == expected
+
+
+def test_ascii_only_rejects_non_ascii_comments():
+    tree = etree.Element("root")
+    tree.append(etree.Comment("Grüße"))
+
+    with pytest.raises(ValueError, match="non-ASCII comment"):
+        exs.serialize(tree, ascii_only=True)
+
+
+def test_unencodable_characters_are_written_as_references_in_latin_1():
+    tree = etree.Element("root", {"name": "Grüße – 模型"})
+    tree.text = "€"
+    expected = (
+        b'<?xml version="1.0" encoding="ISO-8859-1"?>\n'
+        b'<root name="Gr\xfc\xdfe &#x2013; &#x6A21;&#x578B;">&#x20AC;</root>'
+    )
+
+    actual = exs.serialize(
+        tree,
+        encoding="iso-8859-1",
+        declaration=True,
+        newline="\n",
+        trailing_newline=False,
+    )
+
+    assert actual == expected
+    reparsed = etree.fromstring(actual)
+    assert (reparsed.get("name"), reparsed.text) == ("Grüße – 模型", "€")
+
+
+def test_utf_16_output_is_transcoded_as_a_whole():
+    tree = etree.Element("root", {"name": "Grüße"})
+    etree.SubElement(tree, "child").text = "模型"
+
+    actual = exs.serialize(tree, encoding="utf-16", declaration=True)
+
+    assert actual.startswith(b"\xff\xfe") or actual.startswith(b"\xfe\xff")
+    assert actual.decode("utf-16").startswith(
+        '<?xml version="1.0" encoding="UTF-16"?>'
+    )
+    reparsed = etree.fromstring(actual)
+    assert reparsed.get("name") == "Grüße"
+    assert reparsed[0].text == "模型"
+
+
+def test_bom_is_written_once_before_everything_else():
+    document = "<!--Before the root--><root><child/></root>"
+    tree = etree.fromstring(document).getroottree()
+
+    actual = exs.serialize(tree, declaration=True, bom=True)
+
+    assert actual[:3] == b"\xef\xbb\xbf"
+    assert actual[3:].startswith(b"<?xml ")
+    assert actual.count(b"\xef\xbb\xbf") == 1
+    assert etree.fromstring(actual).tag == "root"
+
+
+def test_bom_is_rejected_for_other_encodings():
+    tree = etree.Element("root")
+
+    with pytest.raises(ValueError, match="BOM"):
+        exs.serialize(tree, encoding="latin-1", bom=True)
+
+
+def test_comments_are_written_without_character_references():
+    tree = etree.Element("root")
+    tree.append(etree.Comment("> starts with a bracket & has <tags>"))
+    expected = (
+        b"<root>\n"
+        b"  <!--> starts with a bracket & has <tags>-->\n"
+        b"</root>"
+    )
+
+    actual = exs.serialize(tree, newline="\n", trailing_newline=False)
+
+    assert actual == expected
+    assert etree.fromstring(actual)[0].text == tree[0].text
+
+
+def test_control_characters_in_comments_raise_an_error():
+    with pytest.raises(ValueError, match=r"'\\x1f'"):
+        exs.escape("unit\x1fseparator", context="comment")
+
+    with pytest.raises(ValueError, match=r"'\\x1f'"):
+        exs.escape(
+            "unit\x1fseparator", context="comment", invalid_chars="reference"
+        )
+
+
+def test_control_characters_in_comments_can_be_replaced():
+    actual = exs.escape(
+        "unit\x1fseparator", context="comment", invalid_chars="replace"
+    )
+
+    assert actual == "unit\ufffdseparator"
+
+
+def _comment(text):
+    comment = etree.Comment("placeholder")
+    comment.text = text
+    return comment
+
+
+@pytest.mark.parametrize("text", ["a -- b", "ends with -"])
+def test_invalid_comments_raise_an_error(text):
+    tree = etree.Element("root")
+    tree.append(_comment(text))
+
+    with pytest.raises(ValueError, match="Comment may not contain '--'"):
+        exs.serialize(tree)
+
+
+def test_fix_comments_fixes_nested_and_sibling_comments():
+    tree = etree.fromstring("<!--before--><root><!--nested--></root>")
+    next(tree.itersiblings(preceding=True)).text = "before ---"
+    tree[0].text = "a--b"
+    expected = b"\n<!--before - - - -->\n<root>\n  <!--a- -b-->\n</root>"
+
+    actual = exs.serialize(
+        tree.getroottree(),
+        fix_comments=True,
+        newline="\n",
+        trailing_newline=False,
+    )
+
+    assert actual == expected
+    assert etree.fromstring(actual)[0].text == "a- -b"
+
+
+def test_cdata_tags_write_their_text_as_cdata_section():
+    tree = etree.fromstring("<root><bodies/><name/></root>")
+    tree[0].text = tree[1].text = "<p>A & B</p>"
+    expected = (
+        b"<root>\n"
+        b"  <bodies><![CDATA[<p>A & B</p>]]></bodies>\n"
+        b"  <name>&lt;p>A &amp; B&lt;/p></name>\n"
+        b"</root>"
+    )
+
+    actual = exs.serialize(
+        tree, cdata_tags={"bodies"}, newline="\n", trailing_newline=False
+    )
+
+    assert actual == expected
+    assert etree.fromstring(actual)[0].text == tree[0].text
+
+
+@pytest.mark.parametrize(
+    "text", ["a]]>b", "]]>", "a]]]>b", "]]>]]>", "line 1\n]]>\nline 3"]
+)
+def test_cdata_sections_are_split_at_the_terminator(text):
+    tree = etree.Element("root")
+    tree.text = text
+
+    actual = exs.serialize(tree, cdata_tags={"root"}, newline="\n")
+
+    assert etree.fromstring(actual).text == text
+
+
+@pytest.mark.parametrize("text", ["a\rb", "\ra\r", "a\r\nb", "a\r]]>b"])
+def test_carriage_returns_in_cdata_sections_round_trip(text):
+    tree = etree.Element("root")
+    tree.text = text
+
+    actual = exs.serialize(tree, cdata_tags={"root"}, newline="\n")
+
+    assert b"\r" not in actual
+    assert etree.fromstring(actual).text == text
+
+
+def test_cdata_sections_do_not_count_towards_the_line_length():
+    tree = etree.fromstring("<root><bodies/></root>")
+    tree[0].text = "<p>" + "x" * 100 + "</p>"
+    expected = (
+        b"<root>\n  <bodies><![CDATA["
+        + tree[0].text.encode("ascii")
+        + b"]]></bodies>\n</root>"
+    )
+
+    actual = exs.serialize(
+        tree, cdata_tags={"bodies"}, newline="\n", trailing_newline=False
+    )
+
+    assert actual == expected
+
+
+def test_unencodable_characters_in_cdata_sections_raise_an_error():
+    tree = etree.Element("root")
+    tree.text = "\N{EURO SIGN}"
+
+    with pytest.raises(ValueError, match="in the text of"):
+        exs.serialize(tree, cdata_tags={"root"}, ascii_only=True)
+
+    with pytest.raises(ValueError, match="in the text of"):
+        exs.serialize(tree, cdata_tags={"root"}, encoding="latin-1")
+
+
+def test_serialize_str_is_equivalent_to_decoding_the_bytes():
+    tree = etree.parse(str(CAPELLA_FILE))
+    expected = exs.serialize(tree, declaration=True).decode("utf-8")
+
+    actual = exs.serialize_str(tree, declaration=True)
+
+    assert actual == expected
+
+
+def test_serialize_str_needs_no_more_memory_than_decoding_the_bytes():
+    tree = etree.parse(str(CAPELLA_FILE))
+
+    def peak_memory(func, arg):
+        tracemalloc.start()
+        try:
+            result = func(arg)
+            return result, tracemalloc.get_traced_memory()[1]
+        finally:
+            tracemalloc.stop()
+
+    payload, bytes_peak = peak_memory(exs.serialize, tree)
+    _, decode_peak = peak_memory(bytes.decode, payload)
+    del payload
+    _, str_peak = peak_memory(exs.serialize_str, tree)
+
+    assert str_peak <= bytes_peak + decode_peak
+
+
+@pytest.mark.parametrize("option", ["encoding", "bom"])
+def test_serialize_str_rejects_byte_options(option):
+    with pytest.raises(TypeError, match=option):
+        exs.serialize_str(etree.Element("root"), **{option: "utf-8"})
+
+
+def test_normalize_unicode_composes_decomposed_characters():
+    decomposed = "Cafe\N{COMBINING ACUTE ACCENT}"
+    tree = etree.Element("root", name=decomposed)
+    tree.text = decomposed
+    tree.append(_comment(decomposed))
+    tree[0].tail = decomposed
+    composed = "Caf\N{LATIN SMALL LETTER E WITH ACUTE}"
+    expected = (
+        f'<root name="{composed}">{composed}'
+        f"<!--{composed}-->{composed}</root>"
+    ).encode()
+
+    actual = exs.serialize(
+        tree, normalize_unicode="NFC", newline="\n", trailing_newline=False
+    )
+
+    assert actual == expected
+    parsed = etree.fromstring(actual)
+    assert parsed.get("name") == unicodedata.normalize("NFC", decomposed)
+
+
+def test_unicode_is_not_normalized_by_default():
+    decomposed = "Cafe\N{COMBINING ACUTE ACCENT}"
+    tree = etree.Element("root", name=decomposed)
+
+    actual = exs.serialize(tree)
+
+    assert decomposed.encode() in actual
+
+
+def test_unsupported_normalization_forms_raise_an_error():
+    with pytest.raises(ValueError, match="normalize_unicode"):
+        exs.serialize(etree.Element("root"), normalize_unicode="NFX")
+
+
+@pytest.mark.parametrize(
+    ["policy", "expected"],
+    [
+        ("keep", "a\uFDD0b\U0001FFFEc"),
+        ("reference", "a&#xFDD0;b&#x1FFFE;c"),
+        ("replace", "a\uFFFDb\uFFFDc"),
+        ("strip", "abc"),
+    ],
+)
+def test_escape_handles_noncharacters(policy, expected):
+    actual = exs.escape("a\uFDD0b\U0001FFFEc", noncharacters=policy)
+
+    assert actual == expected
+
+
+def test_noncharacters_raise_an_error_if_requested():
+    with pytest.raises(ValueError, match=r"'\\ufdef'"):
+        exs.escape("a\uFDEFb", noncharacters="error")
+
+
+def test_noncharacters_in_attributes_text_and_tails_are_escaped():
+    tree = etree.fromstring("<root><child/></root>")
+    tree.set("name", "a\uFDD0")
+    tree.text = "b\U0010FFFF"
+    tree[0].tail = "c\uFDEF"
+    expected = b'<root name="a&#xFDD0;">b&#x10FFFF;<child/>c&#xFDEF;</root>'
+
+    actual = exs.serialize(
+        tree, noncharacters="reference", trailing_newline=False
+    )
+
+    assert actual == expected
+
+
+def test_noncharacters_in_comments_cannot_be_referenced():
+    tree = etree.Element("root")
+    tree.append(_comment("a\uFDD0"))
+
+    with pytest.raises(ValueError, match=r"'\\ufdd0'"):
+        exs.serialize(tree, noncharacters="reference")
+
+
+def test_emf_compat_mode_reproduces_capella_files_byte_for_byte():
+    expected = CAPELLA_FILE.read_bytes()
+    buffer = io.BytesIO()
+
+    exs.write(etree.parse(str(CAPELLA_FILE)), buffer, compat="emf")
+
+    assert buffer.getvalue() == expected
+
+
+def test_emf_compat_mode_does_not_escape_tabs_in_text():
+    tree = etree.Element("root", name="a\tb\x7f")
+    tree.text = "a\tb\r\x7f\"]]>"
+    expected = b'<root name="a&#x9;b\x7f">a\tb&#xD;\x7f&quot;]]&gt;</root>'
+
+    actual = exs.serialize(tree, compat="emf", trailing_newline=False)
+
+    assert actual == expected
+
+
+@pytest.mark.parametrize(
+    ["context", "expected"],
+    [("attribute", "a&#x9;b\x7f"), ("text", "a\tb\x7f")],
+)
+def test_escape_supports_emf_compat_mode(context, expected):
+    actual = exs.escape("a\tb\x7f", context=context, compat="emf")
+
+    assert actual == expected
+
+
+class _SurrogateEscapedElement(etree.ElementBase):
+    TAG = "root"
+
+    def keys(self):
+        return ["name"]
+
+    def items(self):
+        name = MOJIBAKE_XML.split(b'"')[1]
+        return [("name", name.decode("utf-8", "surrogateescape"))]
+
+
+def test_surrogate_escapes_are_written_as_the_original_bytes():
+    tree = _SurrogateEscapedElement()
+
+    actual = exs.serialize(
+        tree, string_errors="surrogateescape", trailing_newline=False
+    )
+
+    assert actual == MOJIBAKE_XML
+
+
+def test_surrogate_escapes_raise_an_error_by_default():
+    tree = _SurrogateEscapedElement()
+
+    with pytest.raises(ValueError, match="'name' of element 'root'"):
+        exs.serialize(tree)
+
+
+def test_serialize_str_keeps_surrogate_escapes():
+    tree = _SurrogateEscapedElement()
+    expected = MOJIBAKE_XML.decode("utf-8", "surrogateescape")
+
+    actual = exs.serialize_str(
+        tree, string_errors="surrogateescape", trailing_newline=False
+    )
+
+    assert actual == expected
+
+
+@pytest.mark.parametrize(
+    "options",
+    [{"encoding": "latin-1"}, {"ascii_only": True}, {"errors": "replace"}],
+)
+def test_surrogate_escapes_cannot_be_combined_with_escaping_options(options):
+    with pytest.raises(ValueError, match="surrogate|string_errors"):
+        exs.serialize(
+            etree.Element("root"), string_errors="surrogateescape", **options
+        )
+
+
+def test_serializing_something_else_than_a_tree_raises_a_type_error():
+    with pytest.raises(TypeError, match="not str"):
+        exs.serialize("<root/>")
+
+
+@pytest.mark.parametrize(
+    ["tree", "hint"],
+    [
+        pytest.param("<root/>", "lxml.etree.fromstring()", id="str"),
+        pytest.param(b"<root/>", "lxml.etree.fromstring()", id="bytes"),
+        pytest.param(
+            [etree.Element("a"), etree.Element("b")],
+            "serialize each element separately",
+            id="list",
+        ),
+        pytest.param(
+            (etree.Element("a"),), "append them to a common root", id="tuple"
+        ),
+    ],
+)
+def test_serializing_near_misses_suggests_a_remedy(tree, hint):
+    with pytest.raises(TypeError, match=re.escape(hint)):
+        exs.serialize(tree)
+
+
+def test_serializing_an_element_tree_is_supported():
+    tree = etree.fromstring(NESTED_XML)
+
+    actual = exs.serialize(tree.getroottree())
+
+    assert actual == exs.serialize(tree)
+
+
+def test_processing_instructions_next_to_the_root_raise_a_type_error():
+    tree = etree.fromstring("<?target data?><root/>").getroottree()
+
+    with pytest.raises(TypeError, match=r"<\?target data\?>.*only comments"):
+        exs.serialize(tree)
+
+
+def test_processing_instructions_in_the_tree_raise_a_type_error():
+    tree = etree.fromstring('<root id="r"><?target data?></root>')
+
+    with pytest.raises(TypeError, match=r"<\?target data\?> at index 0 of"):
+        exs.serialize(tree)
+
+
+class _MonkeypatchedAttributesElement(etree.ElementBase):
+    TAG = "root"
+    ATTRIBUTES = ()
+
+    def keys(self):
+        return [k for k, _ in self.ATTRIBUTES]
+
+    def items(self):
+        return list(self.ATTRIBUTES)
+
+
+@pytest.mark.parametrize(
+    ["attributes", "error", "message"],
+    [
+        pytest.param(
+            [("name", 1)], TypeError, "'name' .* not int", id="int-value"
+        ),
+        pytest.param([(1, "x")], TypeError, "not int", id="int-name"),
+        pytest.param([("", "x")], ValueError, "Malformed name ''", id="empty"),
+        pytest.param(
+            [("{urn:a", "x")], ValueError, "Malformed name", id="unclosed"
+        ),
+    ],
+)
+def test_unusual_attributes_raise_a_clean_error(attributes, error, message):
+    element_type = type(
+        "_Element",
+        (_MonkeypatchedAttributesElement,),
+        {"ATTRIBUTES": tuple(attributes)},
+    )
+    tree = element_type()
+
+    with pytest.raises(error, match=message):
+        exs.serialize(tree)
+
+
+def _with_raw_values(tag, *, text=None, tail=None, **attributes):
+    """Create an element with values that lxml refuses to store.
+
+    lxml rejects characters that are not allowed in XML, but custom
+    element classes can report any text and attributes.  The element
+    must be kept referenced while it is in use, otherwise lxml creates
+    a plain element for it again.
+    """
+
+    class Element(etree.ElementBase):
+        TAG = tag
+
+        @property
+        def text(self):
+            return text
+
+        @property
+        def tail(self):
+            return tail
+
+        def get(self, key, default=None):
+            return attributes.get(key, default)
+
+        def keys(self):
+            return list(attributes)
+
+        def items(self):
+            return list(attributes.items())
+
+    return Element()
+
+
+@pytest.mark.parametrize(
+    ["values", "message"],
+    [
+        pytest.param({"text": 42}, "text of element 'child' .* not int"),
+        pytest.param({"tail": 4.2}, "tail of element 'child' .* not float"),
+        pytest.param(
+            {"name": 1}, "attribute 'name' of element 'child' .* not int"
+        ),
+    ],
+)
+def test_non_string_values_raise_a_type_error(values, message):
+    tree = etree.Element("root")
+    child = _with_raw_values("child", **values)
+    tree.append(child)
+
+    with pytest.raises(TypeError, match=message):
+        exs.serialize(tree)
+
+
+def test_namespaces_bound_to_an_empty_prefix_raise_an_error():
+    tree = _with_nsmap("{urn:a}root", {"": "urn:a"})
+
+    with pytest.raises(ValueError, match="'urn:a' .* empty prefix"):
+        exs.serialize(tree)
+
+
+def test_errors_include_the_path_of_the_failing_element():
+    tree = etree.fromstring("<root><child/><child/></root>")
+    leaf = _with_raw_values("leaf", id="leaf-id", name="\uFFFF")
+    tree[1].append(leaf)
+
+    with pytest.raises(ValueError) as excinfo:
+        exs.serialize(tree)
+
+    message = str(excinfo.value)
+    assert message.endswith("\n  at /root/child[2]/leaf (id=leaf-id)")
+    assert "'name'" in message
+
+
+def test_errors_in_comments_include_the_path_of_the_comment():
+    tree = etree.fromstring("<root><a><!--1--><b/><!--2--></a></root>")
+    tree[0][2].text = "a--b"
+
+    with pytest.raises(ValueError, match=r"\n  at /root/a/comment\(\)\[2\]$"):
+        exs.serialize(tree)
+
+
+def test_errors_in_compacted_elements_include_the_path():
+    tree = etree.fromstring("<root><a><b/></a></root>")
+    leaf = _with_raw_values("c", name="\uFFFF")
+    tree[0][0].append(leaf)
+
+    with pytest.raises(ValueError, match=r"\n  at /root/a/b/c$"):
+        exs.serialize(tree, compact=True)
+
+
+def test_an_incompatible_lxml_raises_an_import_error(monkeypatch):
+    monkeypatch.setattr(lxml, "etree", types.ModuleType("etree"))
+    spec = importlib.util.spec_from_file_location("exs_copy", exs.__file__)
+    assert spec is not None and spec.loader is not None
+    module = importlib.util.module_from_spec(spec)
+
+    with pytest.raises(ImportError, match="requires lxml.etree") as info:
+        spec.loader.exec_module(module)
+
+    assert isinstance(info.value.__cause__, AttributeError)
+
+
+def test_unexpected_nodes_are_reported_with_index_and_parent():
+    tree = etree.fromstring(NESTED_XML)
+    tree[0].append(etree.ProcessingInstruction("target", "data"))
+
+    with pytest.raises(TypeError) as info:
+        exs.serialize(tree)
+
+    message = str(info.value)
+    assert "at index 1 of element 'child' (id=child-id)" in message
+    assert "ProcessingInstruction <?target data?>" in message
+
+
+def test_processing_instructions_are_reported_as_unsupported():
+    tree = etree.fromstring(NESTED_XML)
+    tree[0][0].append(etree.ProcessingInstruction("target", "data"))
+
+    with pytest.raises(TypeError, match="instructions.*are not supported"):
+        exs.serialize(tree)
+
+
+def test_a_root_that_is_not_an_element_is_reported():
+    with pytest.raises(TypeError, match="the root must be an element"):
+        exs.serialize(_comment("root"))
+
+
+def test_unexpected_nodes_are_reported_with_a_shortened_repr():
+    tree = etree.fromstring(NESTED_XML)
+    tree[0].append(etree.ProcessingInstruction("target", "x" * 100))
+
+    with pytest.raises(TypeError, match=r"<\?target x{48}\.\.\. at index"):
+        exs.serialize(tree)
+
+
+def test_content_next_to_the_root_is_dropped_silently_by_default():
+    tree = etree.fromstring("<!--before--><root/><!--after-->")
+
+    with warnings.catch_warnings():
+        warnings.simplefilter("error")
+        actual = exs.serialize(tree, on_dropped_content="ignore")
+
+    assert actual == b"<root/>\n"
+
+
+def test_dropped_content_next_to_the_root_can_emit_a_warning():
+    tree = etree.fromstring("<!--before--><root/><!--after-->")
+
+    with pytest.warns(
+        UserWarning, match="2 node.*<!--before-->.*<!--after"
+    ) as record:
+        actual = exs.serialize(tree, on_dropped_content="warn")
+
+    assert actual == b"<root/>\n"
+    assert record[0].filename == __file__
+
+
+def test_dropped_content_next_to_the_root_can_raise_an_error():
+    tree = etree.fromstring("<root/><!--after-->")
+
+    with pytest.raises(ValueError, match="pass the ElementTree"):
+        exs.serialize(tree, on_dropped_content="error")
+
+
+class _FailingWriter:
+    def __init__(self, limit, chunk_size):
+        self.limit = limit
+        self.chunk_size = chunk_size
+        self.data = bytearray()
+
+    def write(self, chunk):
+        if len(self.data) >= self.limit:
+            raise OSError("No space left on device")
+        self.data += chunk[: self.chunk_size]
+        return min(len(chunk), self.chunk_size)
+
+
+def test_write_errors_record_how_much_was_written():
+    tree = etree.fromstring(NESTED_XML)
+    writer = _FailingWriter(limit=100, chunk_size=10)
+
+    with pytest.raises(exs.WriteError, match="after 100 of") as info:
+        exs.write(tree, writer)
+
+    assert info.value.bytes_written == 100
+    assert isinstance(info.value.__cause__, OSError)
+    assert writer.data == exs.serialize(tree, declaration=True)[:100]
+
+
+def test_short_writes_are_continued():
+    tree = etree.fromstring(NESTED_XML)
+    writer = _FailingWriter(limit=math.inf, chunk_size=7)
+
+    exs.write(tree, writer)
+
+    assert writer.data == exs.serialize(tree, declaration=True)
+
+
+class _StuckWriter:
+    def __init__(self, count):
+        self.count = count
+
+    def write(self, chunk):
+        return self.count
+
+
+@pytest.mark.parametrize("count", [0, -1, exs.WRITE_CHUNK_SIZE + 1])
+def test_writes_without_progress_raise_a_write_error(count):
+    tree = etree.fromstring(NESTED_XML)
+
+    with pytest.raises(exs.WriteError, match=f"returned {count}") as info:
+        exs.write(tree, _StuckWriter(count))
+
+    assert info.value.bytes_written == 0
+
+
+def test_problems_are_collected_instead_of_raised():
+    tree = etree.Element("root", {"id": "root-id", "name": "Root"})
+    child = _with_raw_values("child", id="child-id", name="bad \uFFFF")
+    tree.append(child)
+    leaf = etree.SubElement(child, "leaf", {"id": "leaf-id", "name": "Leaf"})
+    leaf.append(etree.ProcessingInstruction("target", "data"))
+    tree.append(_comment("a--b"))
+    problems = []
+
+    actual = exs.serialize(tree, problems=problems)
+
+    parsed = etree.fromstring(actual)
+    assert parsed[0].get("name") == ""
+    assert parsed[0][0].get("name") == "Leaf"
+    assert parsed[1].text == "a- -b"
+    assert [i.path for i in problems] == [
+        "/root/child (id=child-id)",
+        "/root/child/leaf/processing-instruction()",
+        "/root/comment()",
+    ]
+    assert "'name'" in problems[0].message
+    assert "ProcessingInstruction" in problems[1].message
+    assert "'--'" in problems[2].message
+
+
+@pytest.mark.parametrize(
+    ["where", "expected"],
+    [
+        pytest.param(
+            "text", b"<root>\n  <child></child>\n</root>\n", id="text"
+        ),
+        pytest.param("tail", b"<root>\n  <child/>\n</root>\n", id="tail"),
+    ],
+)
+def test_collected_problems_leave_no_partial_text_behind(where, expected):
+    tree = etree.Element("root")
+    child = _with_raw_values("child", **{where: "good\nbad \uFFFF"})
+    tree.append(child)
+    problems = []
+
+    actual = exs.serialize(tree, problems=problems, newline="\n")
+
+    assert actual == expected
+    assert [i.category for i in problems] == ["character"]
+
+
+@pytest.mark.parametrize("compact", [False, True])
+def test_collected_problems_record_the_position_in_the_output(compact):
+    tree = etree.fromstring(
+        '<root id="root-id" name="Root"><child id="child-id" name="Child"/>'
+        "</root>"
+    )
+    leaf = _with_raw_values("leaf", id="leaf-id", name="bad \uFFFF")
+    tree[0].append(leaf)
+    problems = []
+
+    actual = exs.serialize(
+        tree, problems=problems, compact=compact, newline="\n"
+    )
+
+    lines = actual.splitlines()
+    line = next(i for i, text in enumerate(lines, 1) if b"<leaf" in text)
+    leaf = lines[line - 1].index(b"<leaf")
+    column = lines[line - 1].index(b'name="', leaf) + 7
+    assert [(i.line, i.column) for i in problems] == [(line, column)]
+
+
+@pytest.mark.parametrize("compact", [False, True])
+def test_collected_problems_record_the_position_of_each_problem(compact):
+    tree = etree.fromstring(
+        '<root id="root-id" name="Root"><child id="child-id" name="Child"/>'
+        '<other id="other-id" name="Other"/></root>'
+    )
+    leaves = [
+        _with_raw_values("leaf", id=f"leaf-{i}", name=f"bad {i} \uFFFF")
+        for i in range(2)
+    ]
+    tree[0].append(leaves[0])
+    tree[1].append(leaves[1])
+    problems = []
+
+    actual = exs.serialize(
+        tree, problems=problems, compact=compact, newline="\n"
+    )
+
+    expected = []
+    lines = actual.splitlines()
+    for i in range(2):
+        marker = f'id="leaf-{i}"'.encode()
+        line = next(n for n, text in enumerate(lines, 1) if marker in text)
+        leaf = lines[line - 1].index(marker)
+        column = lines[line - 1].index(b'name="', leaf) + 7
+        expected.append((line, column))
+    assert [(i.line, i.column) for i in problems] == expected
+
+
+def test_a_root_that_is_not_an_element_still_raises():
+    with pytest.raises(TypeError, match="Cannot serialize Comment"):
+        exs.serialize(_comment("root"), problems=[])
+
+
+def test_validate_returns_no_problems_for_a_valid_tree():
+    tree = etree.fromstring(NESTED_XML)
+
+    assert exs.validate(tree) == []
+
+
+def test_validate_reports_all_problems_with_categories():
+    tree = etree.Element("root", {"id": "root-id", "name": "Root"})
+    child = _with_raw_values("child", id="child-id", name="bad \uFFFF")
+    tree.append(child)
+    leaf = etree.SubElement(child, "leaf", {"id": "leaf-id", "name": "Leaf"})
+    leaf.append(etree.ProcessingInstruction("target", "data"))
+    tree.append(_comment("a--b"))
+
+    problems = exs.validate(tree)
+
+    assert [(i.path, i.category) for i in problems] == [
+        ("/root/child (id=child-id)", "character"),
+        ("/root/child/leaf/processing-instruction()", "node"),
+        ("/root/comment()", "comment"),
+    ]
+
+
+def test_validate_reports_fatal_problems():
+    problems = exs.validate(_comment("root"))
+
+    assert len(problems) == 1
+    assert problems[0].category == "fatal"
+    assert problems[0].line is None
+    assert "Cannot serialize Comment" in problems[0].message
+
+
+def test_validate_does_not_accept_a_problems_list():
+    with pytest.raises(TypeError, match="problems"):
+        exs.validate(etree.fromstring(NESTED_XML), problems=[])
+
+
+def test_validate_ignores_options_that_only_cause_side_effects():
+    tree = etree.fromstring("<!--before--><root/>")
+    tree.set(f"{{{exs.NS_XMI}}}id", "root-id")
+    etree.SubElement(tree, "child", nsmap={"a": "http://example.com/ns"})
+    etree.SubElement(tree, "child", nsmap={"b": "http://example.com/ns/"})
+    events = []
+
+    with warnings.catch_warnings():
+        warnings.simplefilter("error")
+        problems = exs.validate(
+            tree,
+            trace=events.append,
+            cancel=lambda: True,
+            timeout=0,
+            cancel_interval=1,
+            lint=True,
+            on_dropped_content="warn",
+            check_namespace_conflicts="warn",
+        )
+
+    assert problems == []
+    assert events == []
+
+
+class _ControlCharacterNameElement(etree.ElementBase):
+    TAG = "root"
+
+    def keys(self):
+        return ["na\x85me"]
+
+    def items(self):
+        return [("na\x85me", "value")]
+
+
+def test_lint_warns_about_control_characters_in_names():
+    tree = _ControlCharacterNameElement()
+
+    with pytest.warns(exs.LintWarning, match=r"'na\\x85me'") as record:
+        actual = exs.serialize(tree, lint=True)
+
+    assert actual == exs.serialize(tree)
+    assert record[0].filename == __file__
+
+
+def test_lint_warns_about_oversized_attributes():
+    tree = etree.fromstring(NESTED_XML)
+    tree[0].set("name", "x" * 101)
+
+    with pytest.warns(
+        exs.LintWarning, match="is 101 characters long"
+    ) as record:
+        exs.serialize(tree, lint=True, lint_max_attribute_length=100)
+
+    assert record[0].filename == __file__
+
+
+def test_lint_warns_about_ids_that_are_not_uuids():
+    tree = etree.fromstring(NESTED_XML)
+    tree[0].set(f"{{{exs.NS_XMI}}}id", "child-id")
+
+    with pytest.warns(
+        exs.LintWarning, match="'child-id'.*not shaped"
+    ) as record:
+        exs.serialize(tree, lint=True)
+
+    assert record[0].filename == __file__
+
+
+def test_lint_accepts_the_ids_in_capella_files():
+    tree = etree.parse(str(CAPELLA_FILE))
+
+    with warnings.catch_warnings():
+        warnings.simplefilter("error", exs.LintWarning)
+        exs.serialize(tree, lint=True)
+
+
+def test_lint_is_disabled_by_default():
+    tree = etree.fromstring(NESTED_XML)
+    tree[0].set("name", "x" * (exs.LINT_MAX_ATTRIBUTE_LENGTH + 1))
+
+    with warnings.catch_warnings():
+        warnings.simplefilter("error", exs.LintWarning)
+        exs.serialize(tree)
+
+
+@pytest.mark.parametrize("cdata", [False, True])
+def test_long_texts_are_cancelled_before_they_are_complete(cdata):
+    tree = etree.Element("root")
+    tree.text = "line\n" * 10_000
+    polls = 0
+
+    def cancel():
+        nonlocal polls
+        polls += 1
+        return polls >= 2
+
+    with pytest.raises(exs.SerializationCancelled) as info:
+        exs.serialize(
+            tree,
+            cancel=cancel,
+            cancel_interval=1000,
+            cdata_tags={"root"} if cdata else (),
+        )
+
+    assert info.value.element_path == "/root"
+    assert 0 < info.value.bytes_written < len(tree.text) / 4
+
+
+def test_many_attributes_are_cancelled_before_they_are_complete():
+    tree = etree.Element("root", {f"a{i}": "value" for i in range(10_000)})
+    polls = 0
+
+    def cancel():
+        nonlocal polls
+        polls += 1
+        return polls >= 2
+
+    with pytest.raises(exs.SerializationCancelled) as info:
+        exs.serialize(tree, cancel=cancel, cancel_interval=1000)
+
+    assert info.value.element_path == "/root"
+    assert 0 < info.value.bytes_written < len(exs.serialize(tree)) / 4
+
+
+def test_exceeding_max_output_bytes_raises_an_error():
+    tree = etree.fromstring(NESTED_XML)
+
+    with pytest.raises(exs.OutputLimitExceeded) as info:
+        exs.serialize(tree, max_output_bytes=20)
+
+    assert 0 < info.value.bytes_written <= 20
+    assert info.value.element_path.startswith("/root")
+    assert f"after {info.value.bytes_written} bytes" in str(info.value)
+    assert f"at {info.value.element_path}" in str(info.value)
+
+
+def test_output_within_max_output_bytes_is_unchanged():
+    tree = etree.fromstring(NESTED_XML)
+    expected = exs.serialize(tree)
+
+    actual = exs.serialize(tree, max_output_bytes=len(expected))
+
+    assert actual == expected
+
+
+def test_max_output_bytes_is_checked_before_writing_to_a_file():
+    tree = etree.fromstring(NESTED_XML)
+    file = io.BytesIO()
+
+    with pytest.raises(exs.OutputLimitExceeded):
+        exs.write(tree, file, max_output_bytes=20)
+
+    assert file.getvalue() == b""
+
+
+def test_invalid_characters_raise_a_serialization_error():
+    tree = etree.Element("root", {"id": "root-id", "name": "Root"})
+    child = _with_raw_values("child", id="child-id", name="bad \uFFFF")
+    tree.append(child)
+
+    with pytest.raises(exs.SerializationError) as info:
+        exs.serialize(tree)
+
+    assert info.value.category == "character"
+    assert info.value.element_path == "/root/child (id=child-id)"
+    assert info.value.attribute == "name"
+    assert info.value.bytes_written > 0
+
+
+def test_unresolvable_attribute_names_raise_a_serialization_error():
+    tree = etree.Element("root")
+    child = _with_nsmap("child", {})
+    child.set("{urn:a}name", "Child")
+    tree.append(child)
+
+    with pytest.raises(exs.SerializationError) as info:
+        exs.serialize(tree)
+
+    assert info.value.category == "name"
+    assert info.value.attribute == "{urn:a}name"
+    assert info.value.element_path == "/root/child"
+
+
+def test_exceeding_the_output_limit_is_a_serialization_error():
+    tree = etree.fromstring(NESTED_XML)
+
+    with pytest.raises(exs.SerializationError) as info:
+        exs.serialize(tree, max_output_bytes=20)
+
+    assert info.value.category == "limit"
+
+
+def test_trace_receives_formatting_events():
+    tree = etree.fromstring(NESTED_XML)
+    events = []
+
+    actual = exs.serialize(
+        tree, compact=True, line_length=30, trace=events.append
+    )
+
+    assert events == [
+        ("start_element", "root", 0),
+        ("compact", "root", False),
+        ("wrap", "name", 18),
+        ("start_element", "child", 1),
+        ("compact", "child", False),
+        ("start_element", "leaf", 2),
+        ("flush", len(actual)),
+    ]
+
+
+def test_tracing_does_not_change_the_output():
+    tree = etree.fromstring(NESTED_XML)
+    expected = exs.serialize(tree, compact=True, line_length=30)
+
+    actual = exs.serialize(
+        tree, compact=True, line_length=30, trace=lambda event: None
+    )
+
+    assert actual == expected
+
+
+class _BrokenAttributesElement(etree.ElementBase):
+    TAG = "child"
+
+    def items(self):
+        raise RuntimeError("broken on purpose")
+
+
+def test_internal_errors_are_raised_as_serialization_errors():
+    tree = etree.Element("root")
+    child = _BrokenAttributesElement()
+    tree.append(child)
+
+    with pytest.raises(exs.SerializationError) as info:
+        exs.serialize(tree)
+
+    assert info.value.category == "internal"
+    assert info.value.element_path == "/root/child"
+    assert "RuntimeError: broken on purpose" in str(info.value)
+    assert isinstance(info.value.__cause__, RuntimeError)
+
+
+def test_errors_do_not_leave_a_truncated_file_behind(tmp_path):
+    tree = etree.Element("root")
+    child = _BrokenAttributesElement()
+    tree.append(child)
+    file = tmp_path / "model.capella"
+
+    with pytest.raises(exs.SerializationError):
+        exs.write(tree, file)
+
+    assert not file.exists()
+
+
+def test_errors_report_the_position_in_the_output():
+    tree = etree.fromstring(
+        '<root id="root-id" name="Root"><child id="child-id" name="Child"/>'
+        "</root>"
+    )
+    leaf = _with_raw_values("leaf", id="leaf-id", name="bad \uFFFF")
+    tree[0].append(leaf)
+    lines = exs.serialize(tree, problems=[], newline="\n").splitlines()
+    line = next(i for i, text in enumerate(lines, 1) if b"<leaf" in text)
+    column = lines[line - 1].index(b'name="') + 7
+
+    with pytest.raises(exs.SerializationError) as info:
+        exs.serialize(tree, newline="\n")
+
+    assert f"\n  at output line {line}, column {column}\n" in str(info.value)
+
+
+def test_serialization_can_be_cancelled():
+    tree = etree.Element("root")
+    for _ in range(10_000):
+        etree.SubElement(tree, "child")
+    polls = 0
+
+    def cancel():
+        nonlocal polls
+        polls += 1
+        return polls >= 3
+
+    with pytest.raises(exs.SerializationCancelled) as info:
+        exs.serialize(tree, cancel=cancel, cancel_interval=1000)
+
+    assert polls == 3
+    assert info.value.element_path == "/root/child[2999]"
+    assert 0 < info.value.bytes_written < len(exs.serialize(tree)) / 3
+
+
+def test_serialization_can_time_out():
+    tree = etree.Element("root")
+    for _ in range(100):
+        etree.SubElement(tree, "child")
+
+    with pytest.raises(exs.SerializationCancelled):
+        exs.serialize(tree, timeout=0, cancel_interval=1)
+
+
+def test_serialization_is_not_cancelled_if_cancel_returns_false():
+    tree = etree.fromstring(NESTED_XML)
+
+    actual = exs.serialize(tree, cancel=lambda: False, cancel_interval=1)
+
+    assert actual == exs.serialize(tree)
+
+
+def test_special_attributes_are_ordered_independently_of_the_document():
+    tree = etree.fromstring(
+        f'<root xmlns:xmi="{exs.NS_XMI}" xmlns:xsi="{exs.NS_XSI}" b="2"'
+        ' xsi:noNamespaceSchemaLocation="a.xsd" a="1" xmi:version="2.0"'
+        ' xsi:schemaLocation="urn:a a.xsd" c="3"/>'
+    )
+    expected = (
+        b'<root c="3" xmi:version="2.0" xmlns:xmi="http://www.omg.org/XMI"'
+        b' xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance"'
+        b' xsi:schemaLocation="urn:a a.xsd"'
+        b' xsi:noNamespaceSchemaLocation="a.xsd" b="2" a="1"/>\n'
+    )
+
+    actual = exs.serialize(
+        tree,
+        front_attributes=["c", f"{{{exs.NS_XMI}}}version"],
+        anchor_schema_location=True,
+        line_length=None,
+        newline="\n",
+    )
+
+    assert actual == expected
+
+
+def test_errors_from_the_trace_callback_are_passed_on_unchanged():
+    tree = etree.fromstring(NESTED_XML)
+    error = ValueError("trace failed")
+
+    def trace(event):
+        raise error
+
+    with pytest.raises(ValueError) as info:
+        exs.serialize(tree, trace=trace)
+
+    assert info.value is error
+    assert str(info.value) == "trace failed"
+
+
+def test_errors_from_the_cancel_callback_are_passed_on_unchanged():
+    tree = etree.fromstring(NESTED_XML)
+
+    def cancel():
+        raise RuntimeError("cancel failed")
+
+    with pytest.raises(RuntimeError, match="^cancel failed$"):
+        exs.serialize(tree, cancel=cancel, cancel_interval=1)
+
+
+def test_warnings_turned_into_errors_are_passed_on_unchanged():
+    tree = etree.fromstring(NESTED_XML)
+    tree[0].set(f"{{{exs.NS_XMI}}}id", "child-id")
+
+    with warnings.catch_warnings():
+        warnings.simplefilter("error", exs.LintWarning)
+        with pytest.raises(exs.LintWarning):
+            exs.serialize(tree, lint=True)